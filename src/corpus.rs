@@ -0,0 +1,112 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{
+    indexing::{load, InvertedIndex},
+    preprocessing::load_stopwords_from,
+    ranking::Ranking,
+};
+
+// Everything needed to rank against one corpus, so several can be loaded at once
+pub struct Corpus {
+    pub inverted_index: InvertedIndex,
+    pub doc_lengths: HashMap<u32, u32>,
+    pub stopwords: HashSet<String>,
+    pub num_doc: u32,
+    pub avgdl: u32,
+}
+
+impl Corpus {
+    pub fn new(
+        inverted_index: InvertedIndex,
+        doc_lengths: HashMap<u32, u32>,
+        stopwords: HashSet<String>,
+    ) -> Corpus {
+        let num_doc = doc_lengths.len() as u32;
+        let avgdl = match num_doc {
+            0 => 0,
+            n => doc_lengths.values().sum::<u32>() / n,
+        };
+        Corpus {
+            inverted_index,
+            doc_lengths,
+            stopwords,
+            num_doc,
+            avgdl,
+        }
+    }
+
+    // Loads inverted_index.json, doc_lengths.json and stopwords.txt from the directory
+    pub fn load(dir: &str) -> Result<Corpus, Box<dyn std::error::Error>> {
+        let dir = Path::new(dir);
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let inverted_index: InvertedIndex = load(&path("inverted_index.json"))?;
+        let doc_lengths: HashMap<u32, u32> = load(&path("doc_lengths.json"))?;
+        let stopwords = load_stopwords_from(&path("stopwords.txt"))?;
+        Ok(Corpus::new(inverted_index, doc_lengths, stopwords))
+    }
+
+    pub fn ranking(&self, k1: f32, b: f32) -> Ranking<'_> {
+        Ranking::init(&self.doc_lengths, &self.inverted_index, k1, b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        indexing::save,
+        preprocessing::{process_query, Query},
+    };
+    use std::fs;
+
+    // Saves a corpus of the documents given as (doc_id, token) in a new temporary directory
+    fn save_corpus(name: &str, docs: &[(u32, &str)]) -> String {
+        let dir = std::env::temp_dir().join(format!("assignment1_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut index: InvertedIndex = HashMap::new();
+        for (doc_id, token) in docs {
+            index
+                .entry(token.to_string())
+                .or_default()
+                .insert(*doc_id, 1);
+        }
+        let doc_lengths: HashMap<u32, u32> = docs.iter().map(|(doc_id, _)| (*doc_id, 1)).collect();
+        let dir = dir.to_string_lossy().into_owned();
+        save(&index, &format!("{}/inverted_index.json", dir)).unwrap();
+        save(&doc_lengths, &format!("{}/doc_lengths.json", dir)).unwrap();
+        fs::write(format!("{}/stopwords.txt", dir), "the\nof\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn corpora_are_ranked_independently() {
+        let dirs = [
+            save_corpus("first", &[(1, "cell"), (2, "gene")]),
+            save_corpus("second", &[(7, "cell"), (8, "cell")]),
+        ];
+        let first = Corpus::load(&dirs[0]).unwrap();
+        let second = Corpus::load(&dirs[1]).unwrap();
+        dirs.iter().for_each(|dir| fs::remove_dir_all(dir).unwrap());
+        let query = process_query(
+            Query {
+                _id: "1".to_string(),
+                text: "the cells".to_string(),
+                metadata: HashMap::new(),
+            },
+            &first.stopwords,
+        )
+        .unwrap();
+        let doc_ids = |corpus: &Corpus| -> Vec<u32> {
+            let ranking = corpus.ranking(1.2, 0.75);
+            let results = ranking.rank_query(&query);
+            results.iter().rev().map(|result| result.doc_id).collect()
+        };
+        assert_eq!(doc_ids(&first), vec![1]);
+        assert_eq!(doc_ids(&second), vec![7, 8]);
+        assert_eq!((first.num_doc, second.num_doc), (2, 2));
+    }
+}
@@ -0,0 +1,612 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter},
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{preprocessing::TokenizedQuery, ranking::RankingResult};
+
+// Type alias to define relevance judgements
+// {query_id: {doc_id, relevance}, ...}
+pub type Qrels = HashMap<u32, HashMap<u32, u32>>;
+
+// Number of standard recall levels (0.0, 0.1, ..., 1.0)
+const RECALL_LEVELS: usize = 11;
+
+// Reads both the TREC (query_id 0 doc_id relevance) and the BEIR tsv (query-id corpus-id score) formats
+pub fn load_qrels(file_path: &str) -> Result<Qrels, Box<dyn std::error::Error>> {
+    let mut qrels: Qrels = HashMap::new();
+    let file = File::open(file_path)?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (query_id, doc_id, relevance) = match fields.as_slice() {
+            [q, _, d, r] => (q, d, r),
+            [q, d, r] => (q, d, r),
+            _ => continue,
+        };
+        // skip the header line
+        let Ok(query_id) = query_id.parse::<u32>() else {
+            continue;
+        };
+        qrels
+            .entry(query_id)
+            .or_default()
+            .insert(doc_id.parse::<u32>()?, relevance.parse::<u32>()?);
+    }
+    Ok(qrels)
+}
+
+pub fn relevant_docs(qrels: &Qrels, query_id: u32) -> HashSet<u32> {
+    qrels.get(&query_id).map_or(HashSet::new(), |judgements| {
+        judgements
+            .iter()
+            .filter(|(_, relevance)| **relevance > 0)
+            .map(|(doc_id, _)| *doc_id)
+            .collect()
+    })
+}
+
+// Interpolated (recall, precision) pairs at the 11 standard recall levels.
+// Empty if the query has no relevant documents.
+pub fn precision_recall_curve(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+) -> Vec<(f32, f32)> {
+    let relevant = relevant_docs(qrels, query_id);
+    if relevant.is_empty() {
+        return vec![];
+    }
+
+    // (recall, precision) at the rank of each relevant document retrieved
+    let mut points: Vec<(f32, f32)> = vec![];
+    let mut hits = 0;
+    if let Some(ranking) = results.get(&query_id) {
+        for (rank, result) in ranking.iter().rev().enumerate() {
+            if relevant.contains(&result.doc_id) {
+                hits += 1;
+                points.push((
+                    hits as f32 / relevant.len() as f32,
+                    hits as f32 / (rank + 1) as f32,
+                ));
+            }
+        }
+    }
+
+    (0..RECALL_LEVELS)
+        .map(|level| {
+            let recall = level as f32 / (RECALL_LEVELS - 1) as f32;
+            // interpolated precision is the best precision at any recall >= this level
+            let precision = points
+                .iter()
+                .filter(|(r, _)| *r >= recall - f32::EPSILON)
+                .map(|(_, p)| *p)
+                .fold(0.0, f32::max);
+            (recall, precision)
+        })
+        .collect()
+}
+
+// Curve averaged over the queries in the qrels, skipping those without relevant documents
+pub fn average_precision_recall_curve(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+) -> Vec<(f32, f32)> {
+    let curves: Vec<Vec<(f32, f32)>> = qrels
+        .keys()
+        .map(|query_id| precision_recall_curve(results, qrels, *query_id))
+        .filter(|curve| !curve.is_empty())
+        .collect();
+    if curves.is_empty() {
+        return vec![];
+    }
+
+    (0..RECALL_LEVELS)
+        .map(|level| {
+            let precision = curves.iter().map(|curve| curve[level].1).sum::<f32>();
+            (curves[0][level].0, precision / curves.len() as f32)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Metric {
+    AveragePrecision,
+    // nDCG over the first k results, with the relevance as gain
+    Ndcg { k: usize },
+    // Fraction of the first k results that are relevant
+    Precision { k: usize },
+    // Fraction of the relevant documents in the first k results
+    Recall { k: usize },
+    // Precision at rank R, R being the number of relevant documents of the query
+    RPrecision,
+}
+
+// Mean of the precisions at the rank of each relevant document retrieved
+pub fn average_precision(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+) -> f32 {
+    let relevant = relevant_docs(qrels, query_id);
+    if relevant.is_empty() {
+        return 0.0;
+    }
+    let mut hits = 0;
+    let mut precision_sum = 0.0;
+    if let Some(ranking) = results.get(&query_id) {
+        for (rank, result) in ranking.iter().rev().enumerate() {
+            if relevant.contains(&result.doc_id) {
+                hits += 1;
+                precision_sum += hits as f32 / (rank + 1) as f32;
+            }
+        }
+    }
+    precision_sum / relevant.len() as f32
+}
+
+pub fn ndcg(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+    k: usize,
+) -> f32 {
+    let Some(judgements) = qrels.get(&query_id) else {
+        return 0.0;
+    };
+    let discounted = |rank: usize, relevance: u32| relevance as f32 / (rank as f32 + 2.0).log2();
+
+    let dcg: f32 = results.get(&query_id).map_or(0.0, |ranking| {
+        ranking
+            .iter()
+            .rev()
+            .take(k)
+            .enumerate()
+            .map(|(rank, result)| discounted(rank, *judgements.get(&result.doc_id).unwrap_or(&0)))
+            .sum()
+    });
+
+    let mut ideal: Vec<u32> = judgements.values().copied().collect();
+    ideal.sort_by(|a, b| b.cmp(a));
+    let idcg: f32 = ideal
+        .into_iter()
+        .take(k)
+        .enumerate()
+        .map(|(rank, relevance)| discounted(rank, relevance))
+        .sum();
+    match idcg > 0.0 {
+        true => dcg / idcg,
+        false => 0.0,
+    }
+}
+
+pub fn precision_at_k(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+    k: usize,
+) -> f32 {
+    if k == 0 {
+        return 0.0;
+    }
+    let relevant = relevant_docs(qrels, query_id);
+    let hits = results.get(&query_id).map_or(0, |ranking| {
+        ranking
+            .iter()
+            .rev()
+            .take(k)
+            .filter(|result| relevant.contains(&result.doc_id))
+            .count()
+    });
+    hits as f32 / k as f32
+}
+
+pub fn recall_at_k(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+    k: usize,
+) -> f32 {
+    let relevant = relevant_docs(qrels, query_id);
+    if relevant.is_empty() {
+        return 0.0;
+    }
+    let hits = results.get(&query_id).map_or(0, |ranking| {
+        ranking
+            .iter()
+            .rev()
+            .take(k)
+            .filter(|result| relevant.contains(&result.doc_id))
+            .count()
+    });
+    hits as f32 / relevant.len() as f32
+}
+
+// Precision and recall are equal at rank R
+pub fn r_precision(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+) -> f32 {
+    let r = relevant_docs(qrels, query_id).len();
+    precision_at_k(results, qrels, query_id, r)
+}
+
+pub fn query_metric(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    query_id: u32,
+    metric: Metric,
+) -> f32 {
+    match metric {
+        Metric::AveragePrecision => average_precision(results, qrels, query_id),
+        Metric::Ndcg { k } => ndcg(results, qrels, query_id, k),
+        Metric::Precision { k } => precision_at_k(results, qrels, query_id, k),
+        Metric::Recall { k } => recall_at_k(results, qrels, query_id, k),
+        Metric::RPrecision => r_precision(results, qrels, query_id),
+    }
+}
+
+// Metric of every query with relevant documents, computed in parallel, and their mean.
+// The mean is summed in query id order so it does not depend on the thread scheduling.
+pub fn evaluate(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    metric: Metric,
+) -> (BTreeMap<u32, f32>, f32) {
+    let query_ids: Vec<u32> = qrels
+        .keys()
+        .filter(|query_id| !relevant_docs(qrels, **query_id).is_empty())
+        .copied()
+        .collect();
+    let per_query: BTreeMap<u32, f32> = query_ids
+        .into_par_iter()
+        .map(|query_id| (query_id, query_metric(results, qrels, query_id, metric)))
+        .collect();
+    let mean = match per_query.len() {
+        0 => 0.0,
+        n => per_query.values().sum::<f32>() / n as f32,
+    };
+    (per_query, mean)
+}
+
+// Cutoff of the nDCG in a MetricSet
+pub const METRIC_SET_NDCG_K: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSet {
+    pub map: f32,
+    // nDCG@METRIC_SET_NDCG_K
+    pub ndcg: f32,
+    // Number of queries the means are over
+    pub queries: usize,
+}
+
+// MAP and nDCG of the queries grouped by the labels of their metadata. A query with several
+// labels counts in each of their groups, queries without relevant documents are skipped
+pub fn evaluate_by_label(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+    queries: &[TokenizedQuery],
+) -> HashMap<String, MetricSet> {
+    // {label: {query_id, ...}}
+    let mut groups: HashMap<String, BTreeSet<u32>> = HashMap::new();
+    for query in queries {
+        let query_id = query._id;
+        if relevant_docs(qrels, query_id).is_empty() {
+            continue;
+        }
+        for metadata in query.metadata.values().flatten() {
+            groups
+                .entry(metadata.label.clone())
+                .or_default()
+                .insert(query_id);
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(label, query_ids)| {
+            let mean = |metric: Metric| {
+                query_ids
+                    .iter()
+                    .map(|query_id| query_metric(results, qrels, *query_id, metric))
+                    .sum::<f32>()
+                    / query_ids.len() as f32
+            };
+            let metric_set = MetricSet {
+                map: mean(Metric::AveragePrecision),
+                ndcg: mean(Metric::Ndcg {
+                    k: METRIC_SET_NDCG_K,
+                }),
+                queries: query_ids.len(),
+            };
+            (label, metric_set)
+        })
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QueryMetrics {
+    pub average_precision: f32,
+    pub ndcg_at_10: f32,
+    pub p_at_10: f32,
+}
+
+// Means over the queries with relevant documents, written as JSON for CI to compare to a baseline
+#[derive(Serialize, Debug, Clone)]
+pub struct EvaluationSummary {
+    pub map: f32,
+    pub ndcg_at_10: f32,
+    pub p_at_10: f32,
+    pub per_query: HashMap<u32, QueryMetrics>,
+}
+
+pub fn summarize(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    qrels: &Qrels,
+) -> EvaluationSummary {
+    let (average_precisions, map) = evaluate(results, qrels, Metric::AveragePrecision);
+    let (ndcgs, ndcg_at_10) = evaluate(results, qrels, Metric::Ndcg { k: 10 });
+    let (precisions, p_at_10) = evaluate(results, qrels, Metric::Precision { k: 10 });
+    let per_query = average_precisions
+        .iter()
+        .map(|(query_id, average_precision)| {
+            let metrics = QueryMetrics {
+                average_precision: *average_precision,
+                ndcg_at_10: ndcgs[query_id],
+                p_at_10: precisions[query_id],
+            };
+            (*query_id, metrics)
+        })
+        .collect();
+    EvaluationSummary {
+        map,
+        ndcg_at_10,
+        p_at_10,
+        per_query,
+    }
+}
+
+pub fn write_evaluation_summary(
+    summary: &EvaluationSummary,
+    file_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file = File::create(file_path)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), summary)?;
+    Ok(())
+}
+
+// Number of results returned for each query, at most top_k
+pub fn coverage_report(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    top_k: usize,
+) -> HashMap<u32, usize> {
+    results
+        .iter()
+        .map(|(query_id, ranking)| (*query_id, ranking.len().min(top_k)))
+        .collect()
+}
+
+// Queries that returned no documents, likely all their terms are out of the vocabulary
+pub fn zero_result_queries(coverage: &HashMap<u32, usize>) -> Vec<u32> {
+    let mut query_ids: Vec<u32> = coverage
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(query_id, _)| *query_id)
+        .collect();
+    query_ids.sort();
+    query_ids
+}
+
+pub fn print_coverage_summary(coverage: &HashMap<u32, usize>, top_k: usize) {
+    let short = coverage.values().filter(|count| **count < top_k).count();
+    println!(
+        "{} of {} queries returned fewer than {} documents",
+        short,
+        coverage.len(),
+        top_k
+    );
+    let zero = zero_result_queries(coverage);
+    if !zero.is_empty() {
+        println!("Queries without any result: {:?}", zero);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // {query_id: results} from (query_id, doc_id) pairs, each query's results best first
+    fn ranked(pairs: &[(u32, u32)]) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        let mut results: BTreeMap<u32, BTreeSet<RankingResult>> = BTreeMap::new();
+        for (n, (query_id, doc_id)) in pairs.iter().enumerate() {
+            results.entry(*query_id).or_default().insert(RankingResult {
+                query_id: *query_id,
+                doc_id: *doc_id,
+                score: (pairs.len() - n) as f32,
+            });
+        }
+        results
+    }
+
+    fn qrels(judgements: &[(u32, u32, u32)]) -> Qrels {
+        let mut qrels: Qrels = HashMap::new();
+        for (query_id, doc_id, relevance) in judgements {
+            qrels
+                .entry(*query_id)
+                .or_default()
+                .insert(*doc_id, *relevance);
+        }
+        qrels
+    }
+
+    #[test]
+    fn precision_recall_curve_of_a_small_ranking() {
+        let results = ranked(&[(1, 10), (1, 11), (1, 12), (2, 20)]);
+        let qrels = qrels(&[(1, 10, 1), (1, 12, 1), (2, 20, 0)]);
+        // relevant documents at ranks 1 and 3: (recall 0.5, precision 1) and (recall 1, precision 2/3)
+        let curve = precision_recall_curve(&results, &qrels, 1);
+        assert_eq!(curve.len(), 11);
+        for (level, (recall, precision)) in curve.iter().enumerate() {
+            assert!((recall - level as f32 / 10.0).abs() < 1e-6);
+            let expected = if level <= 5 { 1.0 } else { 2.0 / 3.0 };
+            assert!((precision - expected).abs() < 1e-6);
+        }
+        // query 2 has no relevant documents and is left out of the average
+        assert!(precision_recall_curve(&results, &qrels, 2).is_empty());
+        assert_eq!(average_precision_recall_curve(&results, &qrels), curve);
+    }
+
+    #[test]
+    fn out_of_vocabulary_queries_are_flagged() {
+        use crate::{indexing::InvertedIndex, ranking::Ranking};
+        let index: InvertedIndex = HashMap::from([
+            ("cell".to_string(), HashMap::from([(1, 1), (2, 1)])),
+            ("gene".to_string(), HashMap::from([(3, 1)])),
+        ]);
+        let doc_lengths: HashMap<u32, u32> = HashMap::from([(1, 1), (2, 1), (3, 1)]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let query = |id: u32, token: &str| TokenizedQuery {
+            _id: id,
+            tokens: HashMap::from([(token.to_string(), 1)]),
+            metadata: HashMap::new(),
+            boosts: HashMap::new(),
+            bigrams: HashMap::new(),
+            excluded: HashSet::new(),
+            fingerprint: None,
+        };
+        let results = ranking.rank_documents(&[query(1, "cell"), query(2, "heart")]);
+        let coverage = coverage_report(&results, 1);
+        assert_eq!(coverage, HashMap::from([(1, 1), (2, 0)]));
+        assert_eq!(zero_result_queries(&coverage), vec![2]);
+    }
+
+    #[test]
+    fn parallel_mean_equals_sequential_mean() {
+        let mut pairs: Vec<(u32, u32)> = vec![];
+        let mut judgements: Vec<(u32, u32, u32)> = vec![];
+        for query_id in 1..=20u32 {
+            for doc_id in 0..10u32 {
+                pairs.push((query_id, doc_id));
+                if (doc_id * 7 + query_id) % 4 == 0 {
+                    judgements.push((query_id, doc_id, 1));
+                }
+            }
+        }
+        let results = ranked(&pairs);
+        let qrels = qrels(&judgements);
+        for metric in [
+            Metric::AveragePrecision,
+            Metric::Ndcg { k: 5 },
+            Metric::Recall { k: 3 },
+        ] {
+            let (per_query, mean) = evaluate(&results, &qrels, metric);
+            let mut query_ids: Vec<u32> = qrels.keys().copied().collect();
+            query_ids.sort();
+            let sequential: Vec<f32> = query_ids
+                .iter()
+                .map(|query_id| query_metric(&results, &qrels, *query_id, metric))
+                .collect();
+            assert_eq!(
+                per_query.values().copied().collect::<Vec<f32>>(),
+                sequential
+            );
+            assert_eq!(
+                mean,
+                sequential.iter().sum::<f32>() / sequential.len() as f32
+            );
+        }
+    }
+
+    #[test]
+    fn label_groups_are_evaluated_independently() {
+        use crate::preprocessing::InnerMetadata;
+        let labelled = |query_id: u32, labels: &[&str]| TokenizedQuery {
+            _id: query_id,
+            tokens: HashMap::new(),
+            metadata: labels
+                .iter()
+                .enumerate()
+                .map(|(n, label)| {
+                    let evidence = InnerMetadata {
+                        sentences: vec![0],
+                        label: label.to_string(),
+                    };
+                    ((100 + n).to_string(), vec![evidence])
+                })
+                .collect(),
+            boosts: HashMap::new(),
+            bigrams: HashMap::new(),
+            excluded: HashSet::new(),
+            fingerprint: None,
+        };
+        let queries = [
+            labelled(1, &["SUPPORT"]),
+            labelled(2, &["CONTRADICT"]),
+            labelled(3, &["SUPPORT", "CONTRADICT"]),
+        ];
+        // average precision 1 for queries 1 and 3, 0.5 for query 2
+        let results = ranked(&[(1, 10), (1, 11), (2, 20), (2, 21), (3, 30), (3, 31)]);
+        let qrels = qrels(&[(1, 10, 1), (2, 21, 1), (3, 30, 1)]);
+        let groups = evaluate_by_label(&results, &qrels, &queries);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["SUPPORT"].queries, 2);
+        assert_eq!(groups["SUPPORT"].map, 1.0);
+        assert_eq!(groups["CONTRADICT"].queries, 2);
+        assert_eq!(groups["CONTRADICT"].map, 0.75);
+        assert!(groups["CONTRADICT"].ndcg < groups["SUPPORT"].ndcg);
+    }
+
+    #[test]
+    fn summary_json_has_the_top_level_metrics() {
+        let results = ranked(&[(1, 10), (1, 11), (2, 20), (2, 21)]);
+        let qrels = qrels(&[(1, 10, 1), (2, 21, 1)]);
+        let summary = summarize(&results, &qrels);
+        let path = std::env::temp_dir()
+            .join(format!("assignment1_{}_summary.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        write_evaluation_summary(&summary, &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(json["map"].as_f64(), Some(0.75));
+        assert_eq!(json["p_at_10"].as_f64(), Some(0.1));
+        assert!(json["ndcg_at_10"].is_number());
+        assert_eq!(
+            json["per_query"]["2"]["average_precision"].as_f64(),
+            Some(0.5)
+        );
+    }
+
+    #[test]
+    fn recall_and_r_precision_of_a_small_ranking() {
+        let results = ranked(&[
+            (1, 10),
+            (1, 11),
+            (1, 12),
+            (1, 13),
+            (2, 20),
+            (2, 21),
+            (3, 30),
+        ]);
+        // R is 3 for query 1 and 1 for query 2, query 3 has no relevant document
+        let qrels = qrels(&[(1, 10, 1), (1, 12, 1), (1, 14, 1), (2, 21, 1), (3, 30, 0)]);
+        assert_eq!(recall_at_k(&results, &qrels, 1, 2), 1.0 / 3.0);
+        assert_eq!(recall_at_k(&results, &qrels, 1, 4), 2.0 / 3.0);
+        assert_eq!(recall_at_k(&results, &qrels, 2, 2), 1.0);
+        assert_eq!(r_precision(&results, &qrels, 1), 2.0 / 3.0);
+        assert_eq!(r_precision(&results, &qrels, 2), 0.0);
+
+        let (per_query, mean) = evaluate(&results, &qrels, Metric::RPrecision);
+        assert_eq!(per_query.keys().copied().collect::<Vec<u32>>(), [1, 2]);
+        assert!((mean - 1.0 / 3.0).abs() < 1e-6);
+        let (_, mean) = evaluate(&results, &qrels, Metric::Recall { k: 2 });
+        assert!((mean - 2.0 / 3.0).abs() < 1e-6);
+    }
+}
@@ -2,85 +2,1513 @@ use crate::preprocessing::*;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
-    io::{Read, Write},
+    io::{BufWriter, Read, Write},
+    time::{Duration, Instant},
 };
 
+use memmap2::Mmap;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 // Type alias to define inverted index
 // {token: {doc_id, frequency}, ...}
-pub type InvertedIndex = HashMap<String, HashMap<u32, u16>>;
+pub type InvertedIndex = HashMap<String, HashMap<u32, TermFreq>>;
 
-pub fn save<T: Serialize>(container: T, file_path: &str) {
-    let mut file = File::create(file_path).expect("Failed to create file at specified path.");
-    let json_data = serde_json::to_string(&container).expect("Failed to serialize data.");
-    file.write_all(json_data.as_bytes())
-        .expect("Failed to write to file.");
+// Type alias to define positional index
+// {token: {doc_id, [position, ...]}, ...}
+pub type PositionalIndex = HashMap<String, HashMap<u32, Vec<u32>>>;
+
+// Type alias to define phonetic index
+// {soundex code: {doc_id, ...}, ...}
+pub type PhoneticIndex = HashMap<String, HashSet<u32>>;
+
+// Type alias to define sentence index
+// {(doc_id, sentence index): {token: frequency}, ...}
+pub type SentenceIndex = HashMap<(u32, usize), HashMap<String, TermFreq>>;
+
+// Type alias to define the inverted index of each field
+// {field: inverted index}
+pub type FieldIndexes = HashMap<String, InvertedIndex>;
+
+// Type alias to define the length of each field
+// {field: {doc_id, length}}
+pub type FieldLengths = HashMap<String, HashMap<u32, u32>>;
+
+// Type alias to define impact ordered index
+// {token: [(doc_id, term weight), ...]} sorted by term weight descending
+pub type ImpactIndex = HashMap<String, Vec<(u32, f32)>>;
+
+// Controls how the title and text of a document are combined in the index
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub index_title: bool,
+    // Number of times the title tokens are counted when merged with the text, 0 drops them
+    pub title_repeat: u16,
+    // Only the first N tokens of each document (title first, then text) are indexed
+    pub max_doc_tokens: Option<usize>,
+    // Highest frequency of a term in each field (after title_repeat), so a term repeated over and
+    // over in one field can't dominate. Only the fields are capped: the merged postings sum the
+    // capped frequencies, so a term in both the title and the text can reach twice the cap
+    pub max_tf_per_field: Option<TermFreq>,
+    // What the length saved in doc_lengths counts
+    pub length_source: LengthSource,
+    // Preprocessing of the title and text, the queries need the same one (see the fingerprint)
+    pub preprocess: PreprocessConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LengthSource {
+    // Number of tokens of the text, ignoring the title
+    TextOnly,
+    // Number of tokens of the title and text, repeated tokens counted each time
+    TitleAndText,
+    // Number of distinct tokens of the title and text
+    #[default]
+    UniqueTerms,
+}
+
+pub fn document_length(document: &TokenizedDocument, source: LengthSource) -> u32 {
+    match source {
+        LengthSource::TextOnly => document
+            .field(TEXT_FIELD)
+            .map_or(0, |tokens| tokens.values().sum()),
+        LengthSource::TitleAndText => document
+            .fields
+            .values()
+            .flat_map(|tokens| tokens.values())
+            .sum(),
+        LengthSource::UniqueTerms => document.tokens().len() as u32,
+    }
 }
 
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            index_title: true,
+            title_repeat: 1,
+            max_doc_tokens: None,
+            max_tf_per_field: None,
+            length_source: LengthSource::default(),
+            preprocess: PreprocessConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SetupError {
+    IoError(std::io::Error),
+    // A line of a JSONL file that isn't a valid document or query
+    JsonError(serde_json::Error),
+    // A document or query id that isn't a number or doesn't fit in a u32
+    ParseIdError(std::num::ParseIntError),
+}
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetupError::IoError(e) => write!(f, "I/O error: {}", e),
+            SetupError::JsonError(e) => write!(f, "Invalid JSON: {}", e),
+            SetupError::ParseIdError(e) => write!(f, "Invalid id: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+impl From<std::io::Error> for SetupError {
+    fn from(e: std::io::Error) -> Self {
+        SetupError::IoError(e)
+    }
+}
+
+impl From<serde_json::Error> for SetupError {
+    fn from(e: serde_json::Error) -> Self {
+        SetupError::JsonError(e)
+    }
+}
+
+impl From<std::num::ParseIntError> for SetupError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        SetupError::ParseIdError(e)
+    }
+}
+
+// Version of the saved index format, bumped whenever the index layout changes
+pub const INDEX_FORMAT_VERSION: u32 = 1;
+
+// {"version": u32, "index": ...} envelope of a saved index
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionedIndex<T> {
+    pub version: u32,
+    pub index: T,
+}
+
+impl<T> VersionedIndex<T> {
+    pub fn new(index: T) -> VersionedIndex<T> {
+        VersionedIndex {
+            version: INDEX_FORMAT_VERSION,
+            index,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VersionMismatch {
+    pub found: u32,
+    pub expected: u32,
+}
+
+impl std::fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Index format version {} is not supported, expected {}. Rebuild the index with the setup feature",
+            self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+pub fn save<T: Serialize>(container: T, file_path: &str) -> Result<(), SetupError> {
+    let file = File::create(file_path)?;
+    serde_json::to_writer(BufWriter::new(file), &container)?;
+    Ok(())
+}
+
+// Indented output for inspecting the saved files, several times bigger than save's
+pub fn save_pretty<T: Serialize>(container: T, file_path: &str) -> Result<(), SetupError> {
+    let file = File::create(file_path)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), &container)?;
+    Ok(())
+}
+
+// Same as save but map keys are written in sorted order, so saving the same data
+// twice gives identical files. Numeric keys are sorted as strings.
+pub fn save_sorted<T: Serialize>(container: T, file_path: &str) -> Result<(), SetupError> {
+    let mut file = File::create(file_path)?;
+    file.write_all(to_sorted_json(&container)?.as_bytes())?;
+    Ok(())
+}
+
+pub fn to_sorted_json<T: Serialize>(container: &T) -> Result<String, serde_json::Error> {
+    // serde_json's Value keeps object keys in a BTreeMap
+    let value = serde_json::to_value(container)?;
+    serde_json::to_string(&value)
+}
+
+// Files saved in a VersionedIndex envelope are unwrapped, failing with VersionMismatch on another
+// version. Files saved before the envelope existed, and files that never have one, are loaded as they are.
 pub fn load<T: for<'de> Deserialize<'de>>(
     file_path: &str,
 ) -> Result<T, Box<dyn std::error::Error>> {
     let mut file = File::open(file_path)?;
     let mut buf: Vec<u8> = vec![];
     file.read_to_end(&mut buf)?;
-    let data = serde_json::from_slice::<T>(&buf)?;
+    let value = serde_json::from_slice::<serde_json::Value>(&buf)?;
+    match value {
+        // an unversioned index could have "version" and "index" terms, but not a numeric version
+        serde_json::Value::Object(mut map)
+            if map.len() == 2
+                && map.contains_key("index")
+                && map.get("version").is_some_and(|v| v.is_u64()) =>
+        {
+            let found = map["version"].as_u64().unwrap_or(0) as u32;
+            if found != INDEX_FORMAT_VERSION {
+                return Err(Box::new(VersionMismatch {
+                    found,
+                    expected: INDEX_FORMAT_VERSION,
+                }));
+            }
+            let index = map.remove("index").unwrap_or(serde_json::Value::Null);
+            Ok(serde_json::from_value(index)?)
+        }
+        unversioned => Ok(serde_json::from_value(unversioned)?),
+    }
+}
+
+pub fn save_binary<T: Serialize>(container: T, file_path: &str) -> Result<(), SetupError> {
+    let file = File::create(file_path)?;
+    bincode::serialize_into(BufWriter::new(file), &container).map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+pub fn load_binary<T: for<'de> Deserialize<'de>>(
+    file_path: &str,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut file = File::open(file_path)?;
+    let mut buf: Vec<u8> = vec![];
+    file.read_to_end(&mut buf)?;
+    let data = bincode::deserialize::<T>(&buf)?;
+    Ok(data)
+}
+
+// Deserializes straight from the mapped file instead of reading it into a buffer first
+pub fn load_binary_mmap<T: for<'de> Deserialize<'de>>(
+    file_path: &str,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let file = File::open(file_path)?;
+    // Safety: the file is only read and must not be modified while it is mapped
+    let mmap = unsafe { Mmap::map(&file)? };
+    let data = bincode::deserialize::<T>(&mmap)?;
     Ok(data)
 }
 
+// Builds an index from (term, doc_id, frequency) postings, repeated postings have their frequencies summed
+pub fn index_from_triples(
+    triples: impl IntoIterator<Item = (String, u32, TermFreq)>,
+) -> InvertedIndex {
+    let mut index: InvertedIndex = HashMap::new();
+    for (term, doc_id, freq) in triples {
+        *index.entry(term).or_default().entry(doc_id).or_insert(0) += freq;
+    }
+    index
+}
+
+// Writes one term,doc_id,frequency row per posting, sorted by term then doc_id
+pub fn export_index_csv(index: &InvertedIndex, file_path: &str) -> Result<(), SetupError> {
+    let file = File::create(file_path)?;
+    let mut writer = BufWriter::new(file);
+    writeln!(writer, "term,doc_id,frequency")?;
+    let mut terms: Vec<&String> = index.keys().collect();
+    terms.sort();
+    for term in terms {
+        let mut postings: Vec<(&u32, &TermFreq)> = index[term].iter().collect();
+        postings.sort();
+        for (doc_id, freq) in postings {
+            writeln!(writer, "{},{},{}", csv_field(term), doc_id, freq)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn import_index_csv(file_path: &str) -> Result<InvertedIndex, Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(file_path)?);
+    let mut index: InvertedIndex = HashMap::new();
+    for line in reader.lines().skip(1) {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        // doc_id and frequency never contain a comma, so the term is whatever is left
+        let mut columns = line.rsplitn(3, ',');
+        let freq = columns
+            .next()
+            .ok_or("Missing frequency")?
+            .parse::<TermFreq>()?;
+        let doc_id = columns.next().ok_or("Missing doc_id")?.parse::<u32>()?;
+        let term = columns.next().ok_or("Missing term")?;
+        index
+            .entry(unquote_csv_field(term))
+            .or_default()
+            .insert(doc_id, freq);
+    }
+    Ok(index)
+}
+
+// Quotes the field if it contains a comma or a quote, doubling the quotes inside
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn unquote_csv_field(field: &str) -> String {
+    match field.len() >= 2 && field.starts_with('"') && field.ends_with('"') {
+        true => field[1..field.len() - 1].replace("\"\"", "\""),
+        false => field.to_string(),
+    }
+}
+
 pub fn build_inverted_index(documents: Vec<TokenizedDocument>) -> InvertedIndex {
+    let (inverted_index, collisions) = build_inverted_index_with_collisions(documents);
+    if !collisions.is_empty() {
+        eprintln!(
+            "Warning: {} duplicate document ids, later documents overwrote earlier postings: {:?}",
+            collisions.len(),
+            collisions
+        );
+    }
+    inverted_index
+}
+
+// Same index as build_inverted_index, each thread indexes a chunk of the documents and the
+// partial indexes are merged pairwise in parallel
+pub fn build_inverted_index_parallel(documents: &[TokenizedDocument]) -> InvertedIndex {
+    let chunk_size = documents
+        .len()
+        .div_ceil(rayon::current_num_threads())
+        .max(1);
+    documents
+        .par_chunks(chunk_size)
+        .map(|chunk| {
+            let mut index: InvertedIndex = HashMap::new();
+            for doc in chunk {
+                for (token, freq) in doc.tokens() {
+                    index.entry(token).or_default().insert(doc._id, freq);
+                }
+            }
+            index
+        })
+        .reduce(HashMap::new, merge_indexes)
+}
+
+// Merges the postings of right into left. reduce keeps the chunks in order, so like the
+// sequential build a document indexed twice keeps its later postings
+pub fn merge_indexes(mut left: InvertedIndex, right: InvertedIndex) -> InvertedIndex {
+    for (token, doc_map) in right {
+        left.entry(token).or_default().extend(doc_map);
+    }
+    left
+}
+
+// Also returns the document ids seen more than once, their earlier postings were overwritten
+pub fn build_inverted_index_with_collisions(
+    documents: Vec<TokenizedDocument>,
+) -> (InvertedIndex, HashSet<u32>) {
     let mut inverted_index: InvertedIndex = HashMap::new();
+    let mut seen: HashSet<u32> = HashSet::new();
+    let mut collisions: HashSet<u32> = HashSet::new();
     // Token: {doc_id, freq}
     for doc in documents {
-        let tokens = doc.tokens;
+        if !seen.insert(doc._id) {
+            collisions.insert(doc._id);
+        }
+        let tokens = doc.tokens();
         for (token, freq) in tokens {
             // Inserts a key only if it doesnt exist
             // if it does, returns mut reference for updating
-            let token_map = inverted_index.entry(token).or_insert(HashMap::new());
+            let token_map = inverted_index.entry(token).or_default();
             token_map.insert(doc._id, freq);
         }
     }
-    return inverted_index;
-}
-
-pub fn initial_inverted_index_setup() {
-    let stopwords = load_stopwords();
-    let mut documents: Vec<TokenizedDocument> = vec![];
-    let file = File::open("scifact/corpus.jsonl").unwrap();
-    let buffered_reader = BufReader::new(file);
-    let mut document_lengths = HashMap::new();
-    for line in buffered_reader.lines() {
-        let d: Document = serde_json::from_str(line.unwrap().as_str()).expect("msg");
-        let mut text_tokens = preprocess_text(d.text, &stopwords);
-        let title_tokens = preprocess_text(d.title, &stopwords);
-        text_tokens.extend(title_tokens); // combine title token with text tokens
-        document_lengths.insert(d._id.clone(), text_tokens.len().clone() as u32);
-        documents.push(TokenizedDocument {
-            _id: d._id.parse::<u32>().unwrap(),
-            tokens: text_tokens,
-        });
+    (inverted_index, collisions)
+}
+
+pub fn tokenize_document(
+    d: Document,
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+) -> Result<TokenizedDocument, SetupError> {
+    let mut title_words = match config.index_title {
+        true => tokenize_text_with_config(&d.title, stopwords, &config.preprocess),
+        false => vec![],
+    };
+    let mut text_words = tokenize_text_with_config(&d.text, stopwords, &config.preprocess);
+    if let Some(max_doc_tokens) = config.max_doc_tokens {
+        title_words.truncate(max_doc_tokens);
+        text_words.truncate(max_doc_tokens - title_words.len());
+    }
+    let mut text_tokens = count_tokens(text_words);
+    let mut title_tokens = count_tokens(title_words);
+    title_tokens
+        .values_mut()
+        .for_each(|freq| *freq = freq.saturating_mul(TermFreq::from(config.title_repeat)));
+    if let Some(max_tf) = config.max_tf_per_field {
+        for freq in title_tokens.values_mut().chain(text_tokens.values_mut()) {
+            *freq = (*freq).min(max_tf);
+        }
+    }
+    Ok(TokenizedDocument::new(
+        d._id.parse::<u32>()?,
+        title_tokens,
+        text_tokens,
+    ))
+}
+
+// Builds an inverted index for every field instead of flattening them
+pub fn build_field_indexes(documents: &[TokenizedDocument]) -> FieldIndexes {
+    let mut field_indexes: FieldIndexes = HashMap::new();
+    for doc in documents {
+        for (field, tokens) in &doc.fields {
+            let inverted_index = field_indexes.entry(field.clone()).or_default();
+            for (token, freq) in tokens {
+                inverted_index
+                    .entry(token.clone())
+                    .or_default()
+                    .insert(doc._id, *freq);
+            }
+        }
+    }
+    field_indexes
+}
+
+// Takes the tokens of each document in the order they appear
+pub fn build_positional_index(documents: &[(u32, Vec<String>)]) -> PositionalIndex {
+    let mut positional_index: PositionalIndex = HashMap::new();
+    for (doc_id, tokens) in documents {
+        for (position, token) in tokens.iter().enumerate() {
+            positional_index
+                .entry(token.clone())
+                .or_default()
+                .entry(*doc_id)
+                .or_default()
+                .push(position as u32);
+        }
+    }
+    positional_index
+}
+
+// Tokenizes every sentence of the documents' text, sentence indices follow the query metadata
+// Fails with ParseIdError on an id that isn't a u32
+pub fn build_sentence_index(
+    documents: &[Document],
+    stopwords: &HashSet<String>,
+) -> Result<SentenceIndex, SetupError> {
+    let mut sentence_index: SentenceIndex = HashMap::new();
+    for d in documents {
+        let doc_id = d._id.parse::<u32>()?;
+        for (n, sentence) in split_sentences(&d.text).into_iter().enumerate() {
+            sentence_index.insert(
+                (doc_id, n),
+                preprocess_text(sentence.to_string(), stopwords),
+            );
+        }
+    }
+    Ok(sentence_index)
+}
+
+// A sentence of the query metadata missing from the sentence index
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentenceRef {
+    pub doc_id: String,
+    pub sentence: usize,
+}
+
+// Splits the sentences referenced by the query metadata into those found in the sentence index,
+// as its keys, and the out of range ones which are skipped
+pub fn resolve_sentence_refs(
+    metadata: &HashMap<String, Vec<InnerMetadata>>,
+    sentence_index: &SentenceIndex,
+) -> (Vec<(u32, usize)>, Vec<SentenceRef>) {
+    let mut found: Vec<(u32, usize)> = vec![];
+    let mut out_of_range: Vec<SentenceRef> = vec![];
+    for (doc_id, evidence) in metadata {
+        let parsed_id = doc_id.parse::<u32>().ok();
+        for sentence in evidence.iter().flat_map(|e| &e.sentences) {
+            let sentence = *sentence as usize;
+            match parsed_id.filter(|id| sentence_index.contains_key(&(*id, sentence))) {
+                Some(id) => found.push((id, sentence)),
+                None => out_of_range.push(SentenceRef {
+                    doc_id: doc_id.clone(),
+                    sentence,
+                }),
+            }
+        }
+    }
+    found.sort();
+    found.dedup();
+    (found, out_of_range)
+}
+
+// Adds the document to the index, replacing any document already indexed with the same id
+pub fn add_document(
+    index: &mut InvertedIndex,
+    doc_lengths: &mut HashMap<u32, u32>,
+    document: &TokenizedDocument,
+    length_source: LengthSource,
+) {
+    remove_document(index, doc_lengths, document._id);
+    for (token, freq) in document.tokens() {
+        index.entry(token).or_default().insert(document._id, freq);
+    }
+    doc_lengths.insert(document._id, document_length(document, length_source));
+}
+
+// Removes the document from the index, returns false if it wasn't indexed
+pub fn remove_document(
+    index: &mut InvertedIndex,
+    doc_lengths: &mut HashMap<u32, u32>,
+    doc_id: u32,
+) -> bool {
+    if doc_lengths.remove(&doc_id).is_none() {
+        return false;
+    }
+    index.retain(|_, doc_map| {
+        doc_map.remove(&doc_id);
+        !doc_map.is_empty()
+    });
+    true
+}
+
+// Removes the tokens found in fewer than min_df documents and returns how many were removed.
+// When given, the doc_lengths of the indexed documents are recomputed without the removed tokens,
+// counted with the same length_source they were built with.
+pub fn prune_index(
+    index: &mut InvertedIndex,
+    min_df: usize,
+    lengths: Option<(&mut HashMap<u32, u32>, &[TokenizedDocument], LengthSource)>,
+) -> usize {
+    let pruned: Vec<String> = index
+        .iter()
+        .filter(|(_, doc_map)| doc_map.len() < min_df)
+        .map(|(token, _)| token.clone())
+        .collect();
+    let removed: Vec<HashMap<u32, TermFreq>> = pruned
+        .iter()
+        .filter_map(|token| index.remove(token))
+        .collect();
+    if let Some((doc_lengths, documents, length_source)) = lengths {
+        let pruned: HashSet<&String> = pruned.iter().collect();
+        let affected: HashSet<u32> = removed
+            .iter()
+            .flat_map(|doc_map| doc_map.keys().copied())
+            .collect();
+        for document in documents {
+            if !affected.contains(&document._id) || !doc_lengths.contains_key(&document._id) {
+                continue;
+            }
+            let kept = TokenizedDocument {
+                _id: document._id,
+                fields: document
+                    .fields
+                    .iter()
+                    .map(|(field, tokens)| {
+                        let tokens = tokens
+                            .iter()
+                            .filter(|(token, _)| !pruned.contains(token))
+                            .map(|(token, freq)| (token.clone(), *freq))
+                            .collect();
+                        (field.clone(), tokens)
+                    })
+                    .collect(),
+            };
+            doc_lengths.insert(document._id, document_length(&kept, length_source));
+        }
+    }
+    removed.len()
+}
+
+// Indexes the adjacent token pairs of each document under "token1_token2" keys.
+// Takes the tokens of each document in the order they appear
+pub fn build_bigram_index(documents: &[(u32, Vec<String>)]) -> InvertedIndex {
+    let mut bigram_index: InvertedIndex = HashMap::new();
+    for (doc_id, tokens) in documents {
+        for (bigram, freq) in count_bigrams(tokens) {
+            bigram_index
+                .entry(bigram)
+                .or_default()
+                .insert(*doc_id, freq);
+        }
+    }
+    bigram_index
+}
+
+// Indexes the character n-grams of the tokens of each document, see char_ngrams
+pub fn build_char_ngram_index(documents: &[TokenizedDocument], n: usize) -> InvertedIndex {
+    let mut ngram_index: InvertedIndex = HashMap::new();
+    for doc in documents {
+        for (gram, freq) in count_char_ngrams(&doc.tokens(), n) {
+            ngram_index.entry(gram).or_default().insert(doc._id, freq);
+        }
     }
+    ngram_index
+}
+
+// Codes every token of the inverted index, documents of tokens sharing a code are merged
+pub fn build_phonetic_index(inverted_index: &InvertedIndex) -> PhoneticIndex {
+    let mut phonetic_index: PhoneticIndex = HashMap::new();
+    for (token, doc_map) in inverted_index {
+        phonetic_index
+            .entry(soundex(token))
+            .or_default()
+            .extend(doc_map.keys());
+    }
+    phonetic_index
+}
+
+// Length of every field of the documents. UniqueTerms counts the distinct tokens of the field,
+// TextOnly and TitleAndText count every token of the field, repeated tokens each time.
+pub fn build_field_lengths(
+    documents: &[TokenizedDocument],
+    length_source: LengthSource,
+) -> FieldLengths {
+    let mut field_lengths: FieldLengths = HashMap::new();
+    for doc in documents {
+        for (field, tokens) in &doc.fields {
+            let length = match length_source {
+                LengthSource::UniqueTerms => tokens.len() as u32,
+                LengthSource::TextOnly | LengthSource::TitleAndText => tokens.values().sum(),
+            };
+            field_lengths
+                .entry(field.clone())
+                .or_default()
+                .insert(doc._id, length);
+        }
+    }
+    field_lengths
+}
+
+// Finds the pairs of documents whose token sets have a Jaccard similarity above the threshold
+pub fn find_duplicates(documents: &[TokenizedDocument], jaccard_threshold: f32) -> Vec<(u32, u32)> {
+    let token_sets: Vec<HashSet<String>> = documents
+        .iter()
+        .map(|doc| doc.tokens().into_keys().collect())
+        .collect();
+    let mut duplicates = vec![];
+    for i in 0..documents.len() {
+        for j in (i + 1)..documents.len() {
+            let union = token_sets[i].union(&token_sets[j]).count();
+            if union == 0 {
+                continue;
+            }
+            let intersection = token_sets[i].intersection(&token_sets[j]).count();
+            if intersection as f32 / union as f32 > jaccard_threshold {
+                duplicates.push((documents[i]._id, documents[j]._id));
+            }
+        }
+    }
+    duplicates
+}
+
+#[derive(Debug, Clone)]
+pub struct SetupReport {
+    pub docs_indexed: usize,
+    // From reading the corpus to saving the index
+    pub duration: Duration,
+    pub docs_per_sec: f64,
+    pub vocab_size: usize,
+}
+
+pub fn initial_inverted_index_setup() -> Result<SetupReport, SetupError> {
+    let stopwords = load_stopwords_from("scifact/stopwords.txt")?;
+    initial_inverted_index_setup_with(&stopwords, &IndexConfig::default(), None)
+}
+
+pub fn initial_inverted_index_setup_with_progress(
+    mut progress: impl FnMut(usize, usize),
+) -> Result<SetupReport, SetupError> {
+    let stopwords = load_stopwords_from("scifact/stopwords.txt")?;
+    initial_inverted_index_setup_with(&stopwords, &IndexConfig::default(), Some(&mut progress))
+}
+
+// progress is called with (processed, total) after each document
+// Without a callback the corpus is read only once
+pub fn initial_inverted_index_setup_with(
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<SetupReport, SetupError> {
+    setup_from_corpus("scifact/corpus.jsonl", "saved", stopwords, config, progress)
+}
+
+// Indexes the corpus at corpus_path, saving the files into saved_dir
+fn setup_from_corpus(
+    corpus_path: &str,
+    saved_dir: &str,
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+    progress: Option<&mut dyn FnMut(usize, usize)>,
+) -> Result<SetupReport, SetupError> {
+    // a first pass only counts the documents for the progress total,
+    // it is skipped without a callback and left out of the duration
+    let total = match progress {
+        Some(_) => BufReader::new(File::open(corpus_path)?).lines().count(),
+        None => 0,
+    };
+    let start = Instant::now();
+    let file = File::open(corpus_path)?;
+    let (documents, document_lengths) = match progress {
+        Some(progress) => {
+            tokenize_corpus(BufReader::new(file), total, stopwords, config, progress)?
+        }
+        None => tokenize_corpus(BufReader::new(file), total, stopwords, config, |_, _| {})?,
+    };
     let mut documents_map: HashMap<&u32, Vec<String>> = HashMap::new();
-    for TokenizedDocument { _id, tokens } in &documents {
-        documents_map.insert(_id, tokens.clone().into_keys().collect());
+    for document in &documents {
+        documents_map.insert(&document._id, document.tokens().into_keys().collect());
     }
-    save(&documents_map, "saved/doc_tokens.json");
-    save(&document_lengths, "saved/doc_lengths.json");
+    let saved = |file_name: &str| format!("{}/{}", saved_dir, file_name);
+    save(&documents_map, &saved("doc_tokens.json"))?;
+    save(&document_lengths, &saved("doc_lengths.json"))?;
+    save(
+        PreprocessingFingerprint::new(stopwords, &config.preprocess),
+        &saved("preprocessing_fingerprint.json"),
+    )?;
 
+    let docs_indexed = documents.len();
     let inverted_index = build_inverted_index(documents);
-    save(inverted_index, "saved/inverted_index.json");
+    let vocab_size = inverted_index.len();
+    save(
+        VersionedIndex::new(inverted_index),
+        &saved("inverted_index.json"),
+    )?;
+
+    let duration = start.elapsed();
+    Ok(SetupReport {
+        docs_indexed,
+        duration,
+        docs_per_sec: docs_indexed as f64 / duration.as_secs_f64(),
+        vocab_size,
+    })
+}
+
+// Indexes a JSONL corpus from any reader (file, stdin, in memory buffer)
+// Returns the inverted index and the document lengths
+pub fn build_index_from_reader<R: BufRead>(
+    reader: R,
+    stopwords: &HashSet<String>,
+) -> Result<(InvertedIndex, HashMap<u32, u32>), SetupError> {
+    let (documents, document_lengths) =
+        tokenize_corpus(reader, 0, stopwords, &IndexConfig::default(), |_, _| {})?;
+    Ok((build_inverted_index(documents), document_lengths))
+}
+
+// Indexes documents already in memory, for library use without the scifact files
+// Returns the inverted index and the document lengths
+pub fn index_documents(
+    documents: Vec<Document>,
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+) -> Result<(InvertedIndex, HashMap<u32, u32>), SetupError> {
+    let total = documents.len();
+    let (documents, document_lengths) = tokenize_documents(
+        documents.into_iter().map(Ok),
+        total,
+        stopwords,
+        config,
+        |_, _| {},
+    )?;
+    Ok((build_inverted_index(documents), document_lengths))
+}
+
+// Reads the documents one line at a time as they are tokenized
+// progress is called with (processed, total) after each document
+fn tokenize_corpus<R: BufRead>(
+    reader: R,
+    total: usize,
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+    progress: impl FnMut(usize, usize),
+) -> Result<(Vec<TokenizedDocument>, HashMap<u32, u32>), SetupError> {
+    let documents = reader
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Document>(line?.as_str())?));
+    tokenize_documents(documents, total, stopwords, config, progress)
+}
+
+fn tokenize_documents(
+    documents: impl Iterator<Item = Result<Document, SetupError>>,
+    total: usize,
+    stopwords: &HashSet<String>,
+    config: &IndexConfig,
+    mut progress: impl FnMut(usize, usize),
+) -> Result<(Vec<TokenizedDocument>, HashMap<u32, u32>), SetupError> {
+    let mut tokenized: Vec<TokenizedDocument> = vec![];
+    let mut document_lengths: HashMap<u32, u32> = HashMap::new();
+    for (n, d) in documents.enumerate() {
+        let document = tokenize_document(d?, stopwords, config)?;
+        document_lengths.insert(
+            document._id,
+            document_length(&document, config.length_source),
+        );
+        tokenized.push(document);
+        progress(n + 1, total);
+    }
+    Ok((tokenized, document_lengths))
+}
+
+pub fn initial_positional_index_setup() -> Result<(), SetupError> {
+    let stopwords = load_stopwords_from("scifact/stopwords.txt")?;
+    let mut documents: Vec<(u32, Vec<String>)> = vec![];
+    let file = File::open("scifact/corpus.jsonl")?;
+    for line in BufReader::new(file).lines() {
+        let d: Document = serde_json::from_str(line?.as_str())?;
+        let mut tokens = tokenize_text(&d.text, &stopwords);
+        tokens.extend(tokenize_text(&d.title, &stopwords)); // title follows the text
+        documents.push((d._id.parse::<u32>()?, tokens));
+    }
+    save(
+        build_positional_index(&documents),
+        "saved/positional_index.json",
+    )
 }
 
-pub fn initial_query_setup() {
+pub fn initial_query_setup() -> Result<(), SetupError> {
+    let stopwords = load_stopwords_from("scifact/stopwords.txt")?;
+    initial_query_setup_with(&stopwords, &PreprocessConfig::default())
+}
+
+// Preprocesses the queries with the documents' config, IndexConfig.preprocess
+pub fn initial_query_setup_with(
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> Result<(), SetupError> {
     let mut queries: Vec<Query> = vec![];
-    let file = File::open("scifact/queries.jsonl").unwrap();
+    let file = File::open("scifact/queries.jsonl")?;
     let reader = BufReader::new(file);
     for line in reader.lines() {
-        let q: Query = serde_json::from_str(line.unwrap().as_str()).unwrap();
+        let q: Query = serde_json::from_str(line?.as_str())?;
         queries.push(q);
     }
-    let tokenized = process_queries(queries);
-    save(tokenized, "saved/query_tokens.json");
+    let tokenized = process_queries_with(queries, stopwords, config)?;
+    save(tokenized, "saved/query_tokens.json")
+}
+
+// Problem found on a line of a corpus or queries file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    // Not a valid document or query, with the JSON error
+    Malformed(String),
+    // Id already used on an earlier line
+    DuplicateId { id: String, first_line: usize },
+    // Id that isn't a number or doesn't fit in a u32
+    UnparseableId(String),
+    // Document or query without any text
+    Empty(String),
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationIssue::Malformed(e) => write!(f, "malformed line: {}", e),
+            ValidationIssue::DuplicateId { id, first_line } => {
+                write!(f, "duplicate id {} (first on line {})", id, first_line)
+            }
+            ValidationIssue::UnparseableId(id) => write!(f, "invalid id {:?}", id),
+            ValidationIssue::Empty(id) => write!(f, "{} has no text", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    // Non blank lines read
+    pub lines: usize,
+    // (line number starting at 1, issue)
+    pub issues: Vec<(usize, ValidationIssue)>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+// Parses every line of a JSONL corpus without tokenizing or indexing it
+pub fn validate_corpus(file_path: &str) -> Result<ValidationReport, std::io::Error> {
+    validate_jsonl(file_path, |d: &Document| {
+        (
+            d._id.as_str(),
+            d.title.trim().is_empty() && d.text.trim().is_empty(),
+        )
+    })
+}
+
+pub fn validate_queries(file_path: &str) -> Result<ValidationReport, std::io::Error> {
+    validate_jsonl(file_path, |q: &Query| {
+        (q._id.as_str(), q.text.trim().is_empty())
+    })
+}
+
+// id_and_empty gives the id of a parsed line and whether it has no text
+fn validate_jsonl<T: for<'de> Deserialize<'de>>(
+    file_path: &str,
+    id_and_empty: impl Fn(&T) -> (&str, bool),
+) -> Result<ValidationReport, std::io::Error> {
+    let mut report = ValidationReport::default();
+    // {id: line}
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let file = File::open(file_path)?;
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line_number = n + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        report.lines += 1;
+        let entry: T = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                let issue = ValidationIssue::Malformed(e.to_string());
+                report.issues.push((line_number, issue));
+                continue;
+            }
+        };
+        let (id, empty) = id_and_empty(&entry);
+        if id.parse::<u32>().is_err() {
+            let issue = ValidationIssue::UnparseableId(id.to_string());
+            report.issues.push((line_number, issue));
+        }
+        if let Some(first_line) = seen.get(id) {
+            let issue = ValidationIssue::DuplicateId {
+                id: id.to_string(),
+                first_line: *first_line,
+            };
+            report.issues.push((line_number, issue));
+        } else {
+            seen.insert(id.to_string(), line_number);
+        }
+        if empty {
+            report
+                .issues
+                .push((line_number, ValidationIssue::Empty(id.to_string())));
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Document with the tokens in its text and an empty title
+    fn document(doc_id: u32, text: &[(&str, TermFreq)]) -> TokenizedDocument {
+        let text = text.iter().map(|(t, f)| (t.to_string(), *f)).collect();
+        TokenizedDocument::new(doc_id, HashMap::new(), text)
+    }
+
+    #[test]
+    fn identical_token_sets_are_duplicates() {
+        let documents = vec![
+            document(1, &[("cell", 2), ("protein", 1)]),
+            document(2, &[("protein", 4), ("cell", 1)]),
+            document(3, &[("gene", 1), ("tumor", 1)]),
+            document(4, &[("blood", 1), ("heart", 1)]),
+        ];
+        assert_eq!(find_duplicates(&documents, 0.9), vec![(1, 2)]);
+    }
+
+    fn raw_document(doc_id: &str, title: &str, text: &str) -> Document {
+        Document {
+            _id: doc_id.to_string(),
+            title: title.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn counts(text: &str) -> HashMap<String, TermFreq> {
+        count_tokens(tokenize_text(text, &HashSet::new()))
+    }
+
+    #[test]
+    fn title_and_text_are_kept_apart() {
+        let raw = raw_document("7", "cancer genes", "blood cells and blood");
+        let document = tokenize_document(raw, &HashSet::new(), &IndexConfig::default()).unwrap();
+        assert_eq!(document.field(TITLE_FIELD), Some(&counts("cancer genes")));
+        assert_eq!(
+            document.field(TEXT_FIELD),
+            Some(&counts("blood cells and blood"))
+        );
+        // the flattened path merges the fields
+        let index = build_inverted_index(vec![document]);
+        assert_eq!(
+            index.len(),
+            counts("cancer genes blood cells and blood").len()
+        );
+        assert_eq!(index[&tokenize_text("blood", &HashSet::new())[0]][&7], 2);
+    }
+
+    // One JSON document per line, as in corpus.jsonl
+    fn corpus_jsonl(documents: &[(u32, &str)]) -> String {
+        documents
+            .iter()
+            .map(|(doc_id, text)| {
+                format!(
+                    "{{\"_id\": \"{}\", \"title\": \"\", \"text\": \"{}\"}}\n",
+                    doc_id, text
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_document() {
+        let jsonl = corpus_jsonl(&[(1, "cancer cells"), (2, "blood"), (3, "gene expression")]);
+        let mut calls: Vec<(usize, usize)> = vec![];
+        let config = IndexConfig::default();
+        tokenize_corpus(jsonl.as_bytes(), 3, &HashSet::new(), &config, |n, total| {
+            calls.push((n, total))
+        })
+        .unwrap();
+        assert_eq!(calls, vec![(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn duplicate_ids_are_reported() {
+        let documents = vec![
+            document(1, &[("cell", 1)]),
+            document(2, &[("gene", 1)]),
+            document(1, &[("blood", 2)]),
+        ];
+        let (index, collisions) = build_inverted_index_with_collisions(documents);
+        assert_eq!(collisions, HashSet::from([1]));
+        // the later document is still indexed, under the shared id
+        assert_eq!(index["blood"][&1], 2);
+    }
+
+    // Path of a file in the temporary directory, unique to the test process
+    fn temp_path(name: &str) -> String {
+        let file_name = format!("assignment1_{}_{}", std::process::id(), name);
+        std::env::temp_dir()
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn small_index() -> InvertedIndex {
+        index_from_triples([
+            ("cell".to_string(), 1, 2),
+            ("cell".to_string(), 2, 1),
+            ("gene".to_string(), 2, 3),
+            ("blood".to_string(), 3, 1),
+        ])
+    }
+
+    #[test]
+    fn mmap_load_matches_in_memory_load() {
+        let path = temp_path("index.bin");
+        save_binary(small_index(), &path).unwrap();
+        let mapped: InvertedIndex = load_binary_mmap(&path).unwrap();
+        let loaded: InvertedIndex = load_binary(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mapped, loaded);
+        assert_eq!(mapped, small_index());
+    }
+
+    #[test]
+    fn adjacent_tokens_are_indexed_as_bigrams() {
+        let documents = vec![
+            (
+                1,
+                vec![
+                    "cell".to_string(),
+                    "growth".to_string(),
+                    "cell".to_string(),
+                    "growth".to_string(),
+                ],
+            ),
+            (2, vec!["growth".to_string(), "cell".to_string()]),
+        ];
+        let bigram_index = build_bigram_index(&documents);
+        assert_eq!(bigram_index["cell_growth"], HashMap::from([(1, 2)]));
+        assert_eq!(bigram_index["growth_cell"], HashMap::from([(1, 1), (2, 1)]));
+        assert_eq!(bigram_index.len(), 2);
+    }
+
+    #[test]
+    fn each_sentence_is_indexed_apart() {
+        let documents = vec![raw_document(
+            "4",
+            "",
+            "Cancer cells grow. Blood flows quickly.",
+        )];
+        let sentence_index = build_sentence_index(&documents, &HashSet::new()).unwrap();
+        assert_eq!(sentence_index.len(), 2);
+        assert_eq!(sentence_index[&(4, 0)], counts("Cancer cells grow"));
+        assert_eq!(sentence_index[&(4, 1)], counts("Blood flows quickly"));
+    }
+
+    #[test]
+    fn title_can_be_excluded_or_repeated() {
+        let raw = || raw_document("1", "cancer", "cancer cells");
+        let stem = |word: &str| tokenize_text(word, &HashSet::new()).remove(0);
+        let merged = |config: &IndexConfig| {
+            tokenize_document(raw(), &HashSet::new(), config)
+                .unwrap()
+                .tokens()
+        };
+        let default = merged(&IndexConfig::default());
+        assert_eq!(default[&stem("cancer")], 2);
+        let excluded = merged(&IndexConfig {
+            index_title: false,
+            ..IndexConfig::default()
+        });
+        assert_eq!(excluded[&stem("cancer")], 1);
+        assert_eq!(excluded[&stem("cells")], 1);
+        let doubled = merged(&IndexConfig {
+            title_repeat: 2,
+            ..IndexConfig::default()
+        });
+        assert_eq!(doubled[&stem("cancer")], 3);
+        assert_eq!(doubled[&stem("cells")], 1);
+    }
+
+    #[test]
+    fn rare_terms_are_pruned() {
+        let documents = vec![
+            document(1, &[("cell", 2), ("blood", 3)]),
+            document(2, &[("cell", 1), ("gene", 1)]),
+        ];
+        let mut index = build_inverted_index_parallel(&documents);
+        let mut doc_lengths: HashMap<u32, u32> = documents
+            .iter()
+            .map(|doc| (doc._id, document_length(doc, LengthSource::TitleAndText)))
+            .collect();
+        let lengths = (
+            &mut doc_lengths,
+            documents.as_slice(),
+            LengthSource::TitleAndText,
+        );
+        assert_eq!(prune_index(&mut index, 2, Some(lengths)), 2);
+        assert_eq!(index.keys().collect::<Vec<_>>(), ["cell"]);
+        assert_eq!(doc_lengths, HashMap::from([(1, 2), (2, 1)]));
+    }
+
+    #[test]
+    fn sorted_serialization_is_byte_identical() {
+        let mut reversed: InvertedIndex = HashMap::new();
+        let mut triples: Vec<(String, u32, TermFreq)> = small_index()
+            .into_iter()
+            .flat_map(|(term, doc_map)| doc_map.into_iter().map(move |(d, f)| (term.clone(), d, f)))
+            .collect();
+        triples.sort();
+        for (term, doc_id, freq) in triples.into_iter().rev() {
+            reversed.entry(term).or_default().insert(doc_id, freq);
+        }
+        let paths = [temp_path("sorted_a.json"), temp_path("sorted_b.json")];
+        save_sorted(small_index(), &paths[0]).unwrap();
+        save_sorted(&reversed, &paths[1]).unwrap();
+        let bytes: Vec<Vec<u8>> = paths
+            .iter()
+            .map(|path| std::fs::read(path).unwrap())
+            .collect();
+        paths
+            .iter()
+            .for_each(|path| std::fs::remove_file(path).unwrap());
+        assert_eq!(bytes[0], bytes[1]);
+        assert_eq!(
+            to_sorted_json(&small_index()).unwrap().as_bytes(),
+            bytes[0].as_slice()
+        );
+    }
+
+    #[test]
+    fn index_is_built_from_an_in_memory_reader() {
+        let jsonl = corpus_jsonl(&[(1, "cancer cells"), (2, "cells and blood")]);
+        let stopwords = HashSet::from(["and".to_string()]);
+        let (index, doc_lengths) = build_index_from_reader(jsonl.as_bytes(), &stopwords).unwrap();
+        let stem = |word: &str| tokenize_text(word, &HashSet::new()).remove(0);
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[&stem("cells")], HashMap::from([(1, 1), (2, 1)]));
+        assert_eq!(index[&stem("blood")], HashMap::from([(2, 1)]));
+        assert_eq!(doc_lengths, HashMap::from([(1, 2), (2, 2)]));
+    }
+
+    #[test]
+    fn long_documents_are_truncated() {
+        let documents = vec![
+            raw_document("1", "cancer", "cells blood genes proteins tumors"),
+            raw_document("2", "", "cells blood"),
+        ];
+        let config = IndexConfig {
+            max_doc_tokens: Some(3),
+            length_source: LengthSource::TitleAndText,
+            ..IndexConfig::default()
+        };
+        let (index, doc_lengths) = index_documents(documents, &HashSet::new(), &config).unwrap();
+        assert_eq!(doc_lengths, HashMap::from([(1, 3), (2, 2)]));
+        // the title comes first, then the text until the limit
+        let stem = |word: &str| tokenize_text(word, &HashSet::new()).remove(0);
+        assert!(index[&stem("blood")].contains_key(&1));
+        assert!(!index.contains_key(&stem("genes")));
+    }
+
+    #[test]
+    fn csv_export_round_trips() {
+        let mut index = small_index();
+        // commas and quotes in a term must survive the round trip
+        index.insert("a,\"b\"".to_string(), HashMap::from([(9, 4)]));
+        let path = temp_path("index.csv");
+        export_index_csv(&index, &path).unwrap();
+        let imported = import_index_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(imported, index);
+        assert_eq!(imported["cell"].len(), 2);
+    }
+
+    #[test]
+    fn malformed_lines_give_typed_errors() {
+        let malformed = format!(
+            "{}{{\"_id\": \"2\", \"text\": \n",
+            corpus_jsonl(&[(1, "cells")])
+        );
+        assert!(matches!(
+            build_index_from_reader(malformed.as_bytes(), &HashSet::new()),
+            Err(SetupError::JsonError(_))
+        ));
+        let bad_id = "{\"_id\": \"abc\", \"title\": \"\", \"text\": \"cells\"}\n";
+        assert!(matches!(
+            build_index_from_reader(bad_id.as_bytes(), &HashSet::new()),
+            Err(SetupError::ParseIdError(_))
+        ));
+    }
+
+    #[test]
+    fn versioned_and_legacy_indexes_load() {
+        let path = temp_path("versioned.json");
+        save(VersionedIndex::new(small_index()), &path).unwrap();
+        let loaded: InvertedIndex = load(&path).unwrap();
+        assert_eq!(loaded, small_index());
+
+        let newer = VersionedIndex {
+            version: INDEX_FORMAT_VERSION + 1,
+            index: small_index(),
+        };
+        save(newer, &path).unwrap();
+        let error = load::<InvertedIndex>(&path).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<VersionMismatch>(),
+            Some(&VersionMismatch {
+                found: INDEX_FORMAT_VERSION + 1,
+                expected: INDEX_FORMAT_VERSION,
+            })
+        );
+
+        // saved before the envelope, terms named version and index stay terms
+        let mut legacy = small_index();
+        legacy.insert("version".to_string(), HashMap::from([(1, 1)]));
+        legacy.insert("index".to_string(), HashMap::from([(2, 1)]));
+        save(&legacy, &path).unwrap();
+        let loaded: InvertedIndex = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, legacy);
+    }
+
+    #[test]
+    fn setup_report_counts_the_corpus_lines() {
+        let dir = temp_path("setup");
+        std::fs::create_dir_all(&dir).unwrap();
+        let corpus_path = format!("{}/corpus.jsonl", dir);
+        let jsonl = corpus_jsonl(&[(1, "cancer cells"), (2, "blood"), (3, "gene expression")]);
+        std::fs::write(&corpus_path, jsonl).unwrap();
+        let config = IndexConfig::default();
+        let report = setup_from_corpus(&corpus_path, &dir, &HashSet::new(), &config, None);
+        let index: InvertedIndex = load(&format!("{}/inverted_index.json", dir)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let report = report.unwrap();
+        assert_eq!(report.docs_indexed, 3);
+        assert_eq!(report.vocab_size, index.len());
+    }
+
+    #[test]
+    fn setup_counts_the_total_for_a_callback() {
+        let dir = temp_path("setup_progress");
+        std::fs::create_dir_all(&dir).unwrap();
+        let corpus_path = format!("{}/corpus.jsonl", dir);
+        let jsonl = corpus_jsonl(&[(1, "cancer cells"), (2, "blood"), (3, "gene expression")]);
+        std::fs::write(&corpus_path, jsonl).unwrap();
+        let mut calls: Vec<(usize, usize)> = vec![];
+        let mut progress = |n, total| calls.push((n, total));
+        let config = IndexConfig::default();
+        let report = setup_from_corpus(
+            &corpus_path,
+            &dir,
+            &HashSet::new(),
+            &config,
+            Some(&mut progress),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        report.unwrap();
+        assert_eq!(calls, vec![(1, 3), (2, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn title_only_terms_are_repeated() {
+        let stem = |word: &str| tokenize_text(word, &HashSet::new()).remove(0);
+        for title_repeat in [1, 3, 5] {
+            let config = IndexConfig {
+                title_repeat,
+                ..IndexConfig::default()
+            };
+            let raw = raw_document("4", "prion", "blood cells");
+            let tokens = tokenize_document(raw, &HashSet::new(), &config)
+                .unwrap()
+                .tokens();
+            assert_eq!(tokens[&stem("prion")], TermFreq::from(title_repeat));
+            assert_eq!(tokens[&stem("blood")], 1);
+        }
+    }
+
+    #[test]
+    fn out_of_range_sentences_are_reported_and_skipped() {
+        let documents = vec![raw_document(
+            "8",
+            "",
+            "Cells divide quickly. Blood carries oxygen.",
+        )];
+        let sentence_index = build_sentence_index(&documents, &HashSet::new()).unwrap();
+        assert_eq!(sentence_index.len(), 2);
+        let evidence = InnerMetadata {
+            sentences: vec![1, 200],
+            label: "SUPPORT".to_string(),
+        };
+        let metadata = HashMap::from([("8".to_string(), vec![evidence])]);
+        let (found, out_of_range) = resolve_sentence_refs(&metadata, &sentence_index);
+        assert_eq!(found, [(8, 1)]);
+        assert_eq!(
+            out_of_range,
+            [SentenceRef {
+                doc_id: "8".to_string(),
+                sentence: 200,
+            }]
+        );
+    }
+
+    #[test]
+    fn pretty_output_is_indented_and_round_trips() {
+        let path = temp_path("pretty.json");
+        save_pretty(small_index(), &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        let loaded: InvertedIndex = load(&path).unwrap();
+        save(small_index(), &path).unwrap();
+        let compact = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(written.contains('\n'));
+        assert!(!compact.contains('\n'));
+        assert_eq!(loaded, small_index());
+    }
+
+    #[test]
+    fn parallel_merge_equals_the_sequential_build() {
+        let terms = ["cell", "protein", "gene", "tumor", "blood", "heart", "lung"];
+        // ids repeat every 150 documents, the later postings of a repeated id are kept
+        let documents = || -> Vec<TokenizedDocument> {
+            (0..200u32)
+                .map(|n| {
+                    let tokens: Vec<(&str, TermFreq)> = terms
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| !(n as usize + i).is_multiple_of(3))
+                        .map(|(i, term)| (*term, (n + i as u32) % 4 + 1))
+                        .collect();
+                    document(n % 150, &tokens)
+                })
+                .collect()
+        };
+        let sequential = build_inverted_index(documents());
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let parallel = pool.install(|| build_inverted_index_parallel(&documents()));
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn ids_above_u32_are_a_parse_error() {
+        let jsonl = corpus_jsonl(&[(1, "cells"), (2, "blood")])
+            + "{\"_id\": \"5000000000\", \"title\": \"\", \"text\": \"gene\"}\n";
+        match build_index_from_reader(jsonl.as_bytes(), &HashSet::new()) {
+            Err(SetupError::ParseIdError(e)) => {
+                assert_eq!(e.kind(), &std::num::IntErrorKind::PosOverflow)
+            }
+            other => panic!("expected a ParseIdError, got {:?}", other.map(|_| ())),
+        }
+        let raw = raw_document("5000000000", "", "gene");
+        assert!(matches!(
+            tokenize_document(raw, &HashSet::new(), &IndexConfig::default()),
+            Err(SetupError::ParseIdError(_))
+        ));
+    }
+
+    #[test]
+    fn each_length_source_stores_its_length() {
+        let jsonl =
+            "{\"_id\": \"6\", \"title\": \"cancer cells\", \"text\": \"blood cells blood cells gene\"}\n";
+        // title {cancer, cell}, text {blood: 2, cell: 2, gene: 1}
+        for (length_source, expected) in [
+            (LengthSource::TextOnly, 5),
+            (LengthSource::TitleAndText, 7),
+            (LengthSource::UniqueTerms, 4),
+        ] {
+            let config = IndexConfig {
+                length_source,
+                ..IndexConfig::default()
+            };
+            let (documents, doc_lengths) =
+                tokenize_corpus(jsonl.as_bytes(), 1, &HashSet::new(), &config, |_, _| {}).unwrap();
+            assert_eq!(doc_lengths[&6], expected);
+            let mut index = build_inverted_index(documents);
+            let mut added_lengths = HashMap::new();
+            let document = tokenize_document(
+                serde_json::from_str(jsonl).unwrap(),
+                &HashSet::new(),
+                &config,
+            )
+            .unwrap();
+            add_document(&mut index, &mut added_lengths, &document, length_source);
+            assert_eq!(added_lengths[&6], expected);
+        }
+    }
+
+    #[test]
+    fn validation_reports_line_numbers() {
+        let corpus = format!(
+            "{}{{\"_id\": \"3\", \"text\": \n{}",
+            corpus_jsonl(&[(1, "cells"), (2, "blood")]),
+            corpus_jsonl(&[(1, "gene")])
+        );
+        let path = temp_path("validate_corpus.jsonl");
+        std::fs::write(&path, corpus).unwrap();
+        let report = validate_corpus(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report.lines, 4);
+        assert!(!report.is_valid());
+        assert_eq!(report.issues.len(), 2);
+        assert!(matches!(
+            report.issues[0],
+            (3, ValidationIssue::Malformed(_))
+        ));
+        assert_eq!(
+            report.issues[1],
+            (
+                4,
+                ValidationIssue::DuplicateId {
+                    id: "1".to_string(),
+                    first_line: 1,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn documents_in_memory_are_indexed() {
+        let documents = vec![
+            raw_document("1", "", "blood cells"),
+            raw_document("2", "", "cells cells gene"),
+        ];
+        let stopwords = HashSet::new();
+        let (index, doc_lengths) =
+            index_documents(documents, &stopwords, &IndexConfig::default()).unwrap();
+        let (cell, blood, gene) = (
+            counts("cells").into_keys().next().unwrap(),
+            counts("blood").into_keys().next().unwrap(),
+            counts("gene").into_keys().next().unwrap(),
+        );
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[&cell], HashMap::from([(1, 1), (2, 2)]));
+        assert_eq!(index[&blood], HashMap::from([(1, 1)]));
+        assert_eq!(index[&gene], HashMap::from([(2, 1)]));
+        // distinct terms with the default length source
+        assert_eq!(doc_lengths, HashMap::from([(1, 2), (2, 2)]));
+    }
+
+    #[test]
+    fn field_frequencies_are_capped() {
+        let stem = |word: &str| tokenize_text(word, &HashSet::new()).remove(0);
+        let spam = ["prion"; 10].join(" ");
+        let documents = || vec![raw_document("9", &spam, "prion blood")];
+        let capped = IndexConfig {
+            max_tf_per_field: Some(3),
+            ..IndexConfig::default()
+        };
+        let (index, _) = index_documents(documents(), &HashSet::new(), &capped).unwrap();
+        // 3 from the title and 1 from the text
+        assert_eq!(index[&stem("prion")][&9], 4);
+        let fields = build_field_indexes(&[tokenize_document(
+            documents().remove(0),
+            &HashSet::new(),
+            &capped,
+        )
+        .unwrap()]);
+        assert_eq!(fields[TITLE_FIELD][&stem("prion")][&9], 3);
+        let (uncapped, _) =
+            index_documents(documents(), &HashSet::new(), &IndexConfig::default()).unwrap();
+        assert_eq!(uncapped[&stem("prion")][&9], 11);
+    }
 }
@@ -1,3 +1,7 @@
+pub mod corpus;
+pub mod evaluation;
 pub mod indexing;
 pub mod preprocessing;
 pub mod ranking;
+#[cfg(feature = "test-util")]
+pub mod synthetic;
@@ -3,34 +3,49 @@ use std::collections::HashMap;
 use std::time::Instant;
 use std::{fs::File, io::Write}; //import functions
 
-fn save_vocab(inverted_index: &InvertedIndex) {
-    let mut f = File::create("saved/vocab_sample.txt").unwrap();
-    inverted_index
-        .keys()
-        .collect::<Vec<&String>>()
-        .iter()
-        .enumerate()
-        .for_each(|(n, w)| {
-            if n < 100 {
-                f.write_fmt(format_args!("{w}\n")).unwrap();
-            }
-        });
+// Writes a sample of the vocabulary, called by hand when inspecting an index
+#[allow(dead_code)]
+fn save_vocab(inverted_index: &InvertedIndex) -> Result<(), std::io::Error> {
+    let mut f = File::create("saved/vocab_sample.txt")?;
+    for w in inverted_index.keys().take(100) {
+        f.write_fmt(format_args!("{w}\n"))?;
+    }
+    Ok(())
 }
 
-fn main() {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     // To run the setup code, compile with cargo run --features setup
 
+    // Stopwords are loaded once for both setups
+    #[cfg(feature = "setup")]
+    let stopwords = load_stopwords_from("scifact/stopwords.txt")?;
+    // The queries are preprocessed like the documents
+    #[cfg(feature = "setup")]
+    let config = IndexConfig::default();
+
     // Created the inverted index & doc_length and saved to file
     #[cfg(feature = "setup")]
-    initial_inverted_index_setup();
+    {
+        let report = initial_inverted_index_setup_with(&stopwords, &config, None)?;
+        println!(
+            "Indexed {} documents ({} tokens) in {:?}, {:.0} docs/sec",
+            report.docs_indexed, report.vocab_size, report.duration, report.docs_per_sec
+        );
+    }
 
     // Tokenized the queries and saved to file
     #[cfg(feature = "setup")]
-    initial_query_setup();
-
-    let inverted_index: InvertedIndex = load("saved/inverted_index.json").expect("Failed to load");
-    let queries: Vec<TokenizedQuery> = load("saved/query_tokens.json").expect("Failed to load");
-    let doc_lengths: HashMap<u32, u32> = load("saved/doc_lengths.json").expect("Failed to load");
+    initial_query_setup_with(&stopwords, &config.preprocess)?;
+
+    let inverted_index: InvertedIndex = load("saved/inverted_index.json")?;
+    let queries: Vec<TokenizedQuery> = load("saved/query_tokens.json")?;
+    let doc_lengths: HashMap<u32, u32> = load("saved/doc_lengths.json")?;
+    // Indexes saved before the fingerprint was recorded aren't checked
+    if let Ok(fingerprint) =
+        load::<PreprocessingFingerprint>("saved/preprocessing_fingerprint.json")
+    {
+        verify_queries(&fingerprint, &queries)?;
+    }
     let rank = Ranking::init(&doc_lengths, &inverted_index, 1.2, 0.75);
 
     let start = Instant::now();
@@ -38,7 +53,8 @@ fn main() {
     let duration = start.elapsed();
     println!("{:?}", duration);
 
-    println!("Vocab lengths: {:?}", inverted_index.keys().len());
+    println!("Vocab lengths: {:?}", rank.vocabulary_size());
 
-    save_results_to_file(results, "saved/results.tsv");
+    save_results_to_file(results, "cosine_bm25", "saved/results.tsv", None)?;
+    Ok(())
 }
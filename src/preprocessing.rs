@@ -1,21 +1,127 @@
 use std::{
+    cell::RefCell,
     collections::{HashMap, HashSet},
     fs::File,
     io::{BufRead, BufReader},
+    num::ParseIntError,
 };
 
 use lazy_static::lazy_static;
+use rayon::prelude::*;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::indexing::SetupError;
+
+// Frequency of a term in a document, query or field
+pub type TermFreq = u32;
+
+// Tokens shorter than this are dropped after stemming
+const MIN_TOKEN_LEN: usize = 2;
+
+// Most words kept in a thread's stem cache, it is emptied when full
+const STEM_CACHE_CAPACITY: usize = 50_000;
+
+// Identifies the stemmer in the preprocessing fingerprint
+const STEMMER: &str = "porter";
+
 lazy_static! {
     static ref WORD_REGEX: Regex = Regex::new(r"\w+(?:'\w+)?|[^\w\s]").unwrap();
+    static ref BOOST_REGEX: Regex = Regex::new(r"(\w+)\^(\d+(?:\.\d+)?)").unwrap();
+    static ref EXCLUDE_REGEX: Regex = Regex::new(r"(?:^|\s)-(\w+)").unwrap();
+    static ref DEFAULT_CONFIG: PreprocessConfig = PreprocessConfig::default();
+}
+
+thread_local! {
+    // {word: stem}, one per thread so parallel preprocessing doesn't contend on it
+    // None for the words the stemmer failed on
+    static STEM_CACHE: RefCell<HashMap<String, Option<String>>> = RefCell::new(HashMap::new());
+}
+
+// Options applied identically to documents and queries
+#[derive(Debug, Clone)]
+pub struct PreprocessConfig {
+    // Punctuation kept inside a token instead of discarding it, e.g. '-' keeps "t-cell"
+    allowed_punctuation: Vec<char>,
+    word_regex: Regex,
+    // Reuse the stem of words already seen by this thread
+    cache_stems: bool,
+    // Stem the lowercased words so the stems don't depend on the case of the input
+    lowercase_before_stemming: bool,
+    // Stems of the stopwords, removed after stemming to catch inflected stopwords
+    stemmed_stopwords: Option<HashSet<String>>,
+    // Lowercased words kept as they are instead of stemmed, e.g. "aids" that would become "aid"
+    protected_words: HashSet<String>,
+}
+
+impl Default for PreprocessConfig {
+    fn default() -> Self {
+        PreprocessConfig {
+            allowed_punctuation: vec![],
+            word_regex: WORD_REGEX.clone(),
+            cache_stems: true,
+            lowercase_before_stemming: false,
+            stemmed_stopwords: None,
+            protected_words: HashSet::new(),
+        }
+    }
+}
+
+impl PreprocessConfig {
+    pub fn with_allowed_punctuation(mut self, allowed: &[char]) -> Self {
+        self.allowed_punctuation = allowed.to_vec();
+        if !allowed.is_empty() {
+            let class = regex::escape(&allowed.iter().collect::<String>());
+            let pattern = format!(r"\w+(?:'\w+)?(?:[{class}]\w+(?:'\w+)?)*|[^\w\s]");
+            self.word_regex = Regex::new(&pattern).unwrap();
+        }
+        self
+    }
+
+    // Tokens are the matches of the pattern, it replaces the one built by with_allowed_punctuation
+    pub fn with_word_regex(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        self.word_regex = Regex::new(pattern)?;
+        Ok(self)
+    }
+
+    pub fn with_stem_cache(mut self, cache_stems: bool) -> Self {
+        self.cache_stems = cache_stems;
+        self
+    }
+
+    pub fn with_lowercase_before_stemming(mut self, lowercase: bool) -> Self {
+        self.lowercase_before_stemming = lowercase;
+        self
+    }
+
+    // Also removes the stems matching the stem of a stopword, e.g. "having" once "have" is a stopword
+    pub fn with_stemmed_stopwords(mut self, stopwords: &HashSet<String>) -> Self {
+        self.stemmed_stopwords = Some(
+            stem_words(stopwords.iter().map(String::as_str).collect())
+                .into_iter()
+                .collect(),
+        );
+        self
+    }
+
+    // Words matched case insensitively and passed through lowercased, without stemming
+    pub fn with_protected_words(mut self, words: &HashSet<String>) -> Self {
+        self.protected_words = words.iter().map(|w| w.to_lowercase()).collect();
+        self
+    }
+
+    pub fn allowed_punctuation(&self) -> &[char] {
+        &self.allowed_punctuation
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Query {
+    #[serde(alias = "id")]
     pub _id: String,
+    #[serde(alias = "contents")]
     pub text: String,
+    #[serde(default)]
     pub metadata: HashMap<String, Vec<InnerMetadata>>,
 }
 
@@ -27,86 +133,897 @@ pub struct InnerMetadata {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TokenizedQuery {
-    pub _id: String,
-    pub tokens: HashMap<String, u16>,
+    // Parsed from the query file's string id, and saved back as a string
+    #[serde(with = "string_id")]
+    pub _id: u32,
+    pub tokens: HashMap<String, TermFreq>,
     pub metadata: HashMap<String, Vec<InnerMetadata>>,
+    // {token: boost}, tokens without an entry have a boost of 1.0
+    #[serde(default)]
+    pub boosts: HashMap<String, f32>,
+    // {"token1_token2": frequency} for adjacent tokens
+    #[serde(default)]
+    pub bigrams: HashMap<String, TermFreq>,
+    // Stemmed `-word` terms, documents containing any of them are never returned
+    #[serde(default)]
+    pub excluded: HashSet<String>,
+    // Preprocessing the query went through, None for queries saved before it was recorded
+    #[serde(default)]
+    pub fingerprint: Option<PreprocessingFingerprint>,
+}
+
+impl TokenizedQuery {
+    pub fn boost(&self, term: &str) -> f32 {
+        *self.boosts.get(term).unwrap_or(&1.0)
+    }
+}
+
+// Query ids are numbers written as strings in the query files
+mod string_id {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&id.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+// Hash of everything that changes the tokens produced (stopwords, stemmer, minimum token length,
+// tokenizer), saved with the index to catch queries preprocessed differently than the documents
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreprocessingFingerprint(pub u64);
+
+impl PreprocessingFingerprint {
+    pub fn new(stopwords: &HashSet<String>, config: &PreprocessConfig) -> PreprocessingFingerprint {
+        let mut sorted_stopwords: Vec<&String> = stopwords.iter().collect();
+        sorted_stopwords.sort();
+        let allowed_punctuation: String = config.allowed_punctuation.iter().collect();
+        let mut parts: Vec<&str> = sorted_stopwords.iter().map(|w| w.as_str()).collect();
+        let min_token_len = MIN_TOKEN_LEN.to_string();
+        let mut stemmed_stopwords: Vec<&str> = config
+            .stemmed_stopwords
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        stemmed_stopwords.sort();
+        let mut protected_words: Vec<&str> =
+            config.protected_words.iter().map(String::as_str).collect();
+        protected_words.sort();
+        parts.extend([
+            STEMMER,
+            &min_token_len,
+            config.word_regex.as_str(),
+            &allowed_punctuation,
+            if config.lowercase_before_stemming {
+                "lowercase"
+            } else {
+                ""
+            },
+        ]);
+        if config.stemmed_stopwords.is_some() {
+            parts.push("stemmed_stopwords");
+            parts.extend(stemmed_stopwords);
+        }
+        if !protected_words.is_empty() {
+            parts.push("protected_words");
+            parts.extend(protected_words);
+        }
+
+        // FNV-1a, unlike DefaultHasher it gives the same hash across Rust versions
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for part in parts {
+            // the 0xff separator can't appear in utf-8, so parts can't run into each other
+            for byte in part.bytes().chain([0xff]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        PreprocessingFingerprint(hash)
+    }
+
+    pub fn verify_compatible(
+        &self,
+        other: &PreprocessingFingerprint,
+    ) -> Result<(), FingerprintMismatch> {
+        match self == other {
+            true => Ok(()),
+            false => Err(FingerprintMismatch {
+                index: *self,
+                query: *other,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FingerprintMismatch {
+    pub index: PreprocessingFingerprint,
+    pub query: PreprocessingFingerprint,
+}
+
+impl std::fmt::Display for FingerprintMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Queries were preprocessed differently than the index (fingerprint {:x} instead of {:x})",
+            self.query.0, self.index.0
+        )
+    }
 }
 
+impl std::error::Error for FingerprintMismatch {}
+
+// Checks every query that recorded its fingerprint against the index's one
+pub fn verify_queries(
+    index_fingerprint: &PreprocessingFingerprint,
+    queries: &[TokenizedQuery],
+) -> Result<(), FingerprintMismatch> {
+    for fingerprint in queries.iter().filter_map(|query| query.fingerprint) {
+        index_fingerprint.verify_compatible(&fingerprint)?;
+    }
+    Ok(())
+}
+
+// The aliases and default title also read the id/contents schema (e.g. Pyserini JSONL)
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Document {
+    #[serde(alias = "id")]
     pub _id: String,
+    #[serde(default)]
     pub title: String,
+    #[serde(alias = "contents")]
     pub text: String,
 }
 
+pub const TITLE_FIELD: &str = "title";
+pub const TEXT_FIELD: &str = "text";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TokenizedDocument {
     pub _id: u32,
-    pub tokens: HashMap<String, u16>,
+    // {field: {token: frequency}}
+    pub fields: HashMap<String, HashMap<String, TermFreq>>,
+}
+
+impl TokenizedDocument {
+    pub fn new(
+        _id: u32,
+        title: HashMap<String, TermFreq>,
+        text: HashMap<String, TermFreq>,
+    ) -> TokenizedDocument {
+        TokenizedDocument {
+            _id,
+            fields: HashMap::from([
+                (TITLE_FIELD.to_string(), title),
+                (TEXT_FIELD.to_string(), text),
+            ]),
+        }
+    }
+
+    pub fn field(&self, field: &str) -> Option<&HashMap<String, TermFreq>> {
+        self.fields.get(field)
+    }
+
+    // Tokens of every field combined, frequencies of a token found in several fields are summed
+    pub fn tokens(&self) -> HashMap<String, TermFreq> {
+        let mut tokens: HashMap<String, TermFreq> = HashMap::new();
+        for field_tokens in self.fields.values() {
+            for (token, freq) in field_tokens {
+                *tokens.entry(token.clone()).or_insert(0) += freq;
+            }
+        }
+        tokens
+    }
+}
+
+pub fn extract_words(str: &str) -> Vec<&str> {
+    extract_words_with_config(str, &DEFAULT_CONFIG)
+}
+
+pub fn extract_words_with_config<'t>(str: &'t str, config: &PreprocessConfig) -> Vec<&'t str> {
+    extract_words_with_positions_with_config(str, config)
+        .into_iter()
+        .map(|(_, word)| word)
+        .collect()
+}
+
+// Words with their position, positions count only the words kept by the filters
+pub fn extract_words_with_positions(str: &str) -> Vec<(usize, &str)> {
+    extract_words_with_positions_with_config(str, &DEFAULT_CONFIG)
 }
 
-pub fn extract_words(str: &String) -> Vec<&str> {
-    return WORD_REGEX
-        .find_iter(str.as_str())
+pub fn extract_words_with_positions_with_config<'t>(
+    str: &'t str,
+    config: &PreprocessConfig,
+) -> Vec<(usize, &'t str)> {
+    let allowed = &config.allowed_punctuation;
+    config
+        .word_regex
+        .find_iter(str)
         .map(|m| m.as_str())
-        .filter(|w| w.chars().all(|c| !c.is_digit(10))) //remove numbers
-        .filter(|w| w.chars().all(|c| !c.is_ascii_punctuation())) //remove punctuation
-        .collect();
+        .filter(|w| w.chars().all(|c| !c.is_ascii_digit())) //remove numbers
+        .filter(|w| {
+            w.chars()
+                .all(|c| !c.is_ascii_punctuation() || allowed.contains(&c))
+        }) //remove punctuation not allowlisted
+        .filter(|w| !w.chars().all(|c| allowed.contains(&c))) //remove lone allowlisted punctuation
+        .enumerate()
+        .collect()
 }
 
 pub fn remove_stopwords(words: &mut Vec<&str>, stopwords: &HashSet<String>) {
-    words.retain(|e| !stopwords.contains(*e));
+    // stopwords are stored lowercase, compare against the lowercased word
+    words.retain(|e| !stopwords.contains(&e.to_lowercase()));
 }
 
+// Words the stemmer fails on are left out
 pub fn stem_words(words: Vec<&str>) -> Vec<String> {
-    return words
-        .iter()
-        .map(|w| match stem::get(&w) {
-            Ok(stemmed) => stemmed.to_lowercase(),
-            Err(_e) => String::from(""),
+    stem_words_with_failures(words).0
+}
+
+// Returns the stems and, separately, the words the stemmer failed on
+pub fn stem_words_with_failures(words: Vec<&str>) -> (Vec<String>, Vec<String>) {
+    let mut stems: Vec<String> = vec![];
+    let mut failures: Vec<String> = vec![];
+    for w in words {
+        match stem::get(w) {
+            Ok(stemmed) => stems.push(stemmed.to_lowercase()),
+            Err(_e) => failures.push(w.to_string()),
+        }
+    }
+    (stems, failures)
+}
+
+// Same as stem_words, looking up each word in the thread's stem cache first
+pub fn stem_words_cached(words: Vec<&str>) -> Vec<String> {
+    STEM_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        words
+            .into_iter()
+            .filter_map(|w| match cache.get(w) {
+                Some(stemmed) => stemmed.clone(),
+                None => {
+                    let stemmed = stem_words(vec![w]).pop();
+                    if cache.len() >= STEM_CACHE_CAPACITY {
+                        cache.clear();
+                    }
+                    cache.insert(w.to_string(), stemmed.clone());
+                    stemmed
+                }
+            })
+            .collect()
+    })
+}
+
+// Empties the calling thread's stem cache, e.g. between runs over different corpora
+pub fn clear_stem_cache() {
+    STEM_CACHE.with(|cache| cache.borrow_mut().clear());
+}
+
+// Stems the words as the config says, in order, leaving the protected words unstemmed
+pub fn stem_words_with_config(words: Vec<&str>, config: &PreprocessConfig) -> Vec<String> {
+    let stem = |words: Vec<&str>| match config.cache_stems {
+        true => stem_words_cached(words),
+        false => stem_words(words),
+    };
+    if config.protected_words.is_empty() {
+        return stem(words);
+    }
+    words
+        .into_iter()
+        .flat_map(|w| {
+            let lowercased = w.to_lowercase();
+            match config.protected_words.contains(&lowercased) {
+                true => vec![lowercased],
+                false => stem(vec![w]),
+            }
         })
-        .collect();
+        .collect()
 }
 
-pub fn preprocess_text(str: String, stopwords: &HashSet<String>) -> HashMap<String, u16> {
-    let mut words = extract_words(&str);
+// Returns the tokens in the order they appear in the text, a token's position is its index
+pub fn tokenize_text(str: &str, stopwords: &HashSet<String>) -> Vec<String> {
+    tokenize_text_with_config(str, stopwords, &DEFAULT_CONFIG)
+}
+
+pub fn tokenize_text_with_config(
+    str: &str,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> Vec<String> {
+    let mut words = extract_words_with_config(str, config);
     remove_stopwords(&mut words, stopwords);
-    let mut stemmed_words = stem_words(words);
-    stemmed_words.retain(|w| w.len() > 1); // remove words that ended up being 1 letter or less
-    let mut frequency: HashMap<String, u16> = HashMap::new();
+    let lowercased: Vec<String>;
+    if config.lowercase_before_stemming {
+        lowercased = words.iter().map(|w| w.to_lowercase()).collect();
+        words = lowercased.iter().map(String::as_str).collect();
+    }
+    let mut stemmed_words = stem_words_with_config(words, config);
+    stemmed_words.retain(|w| w.len() >= MIN_TOKEN_LEN); // remove words that ended up being 1 letter or less
+    if let Some(stemmed_stopwords) = &config.stemmed_stopwords {
+        stemmed_words.retain(|w| !stemmed_stopwords.contains(w));
+    }
     stemmed_words
+}
+
+pub fn preprocess_text(str: String, stopwords: &HashSet<String>) -> HashMap<String, TermFreq> {
+    preprocess_text_with_config(str, stopwords, &DEFAULT_CONFIG)
+}
+
+pub fn preprocess_text_with_config(
+    str: String,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> HashMap<String, TermFreq> {
+    count_tokens(tokenize_text_with_config(&str, stopwords, config))
+}
+
+// {token: frequency} of an ordered token stream
+pub fn count_tokens(tokens: Vec<String>) -> HashMap<String, TermFreq> {
+    let mut frequency: HashMap<String, TermFreq> = HashMap::new();
+    tokens
         .into_iter()
         .for_each(|word| *frequency.entry(word).or_insert(0) += 1);
-    return frequency;
+    frequency
 }
 
-pub fn load_stopwords() -> HashSet<String> {
-    let file = File::open("scifact/stopwords.txt").unwrap();
+// Splits the text after each '.', '!' or '?' followed by whitespace or the end of the text
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_boundary = chars.peek().is_none_or(|(_, next)| next.is_whitespace());
+        if matches!(c, '.' | '!' | '?') && at_boundary {
+            sentences.push(&text[start..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    sentences.push(&text[start..]);
+    sentences
+        .into_iter()
+        .map(|sentence| sentence.trim())
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+pub fn bigram_key(first: &str, second: &str) -> String {
+    format!("{first}_{second}")
+}
+
+// Adjacent token pairs with their frequency, tokens must be in the order they appear
+pub fn count_bigrams(tokens: &[String]) -> HashMap<String, TermFreq> {
+    let mut bigrams: HashMap<String, TermFreq> = HashMap::new();
+    for pair in tokens.windows(2) {
+        *bigrams.entry(bigram_key(&pair[0], &pair[1])).or_insert(0) += 1;
+    }
+    bigrams
+}
+
+// Character n-grams of the token padded with '#', so "imune" shares "#im", "mun", "une" and "ne#"
+// with "immune". A token shorter than n is a single n-gram of the padded token
+pub fn char_ngrams(token: &str, n: usize) -> Vec<String> {
+    if n == 0 {
+        return vec![];
+    }
+    let padded: Vec<char> = format!("#{token}#").chars().collect();
+    if padded.len() <= n {
+        return vec![padded.into_iter().collect()];
+    }
+    padded
+        .windows(n)
+        .map(|gram| gram.iter().collect())
+        .collect()
+}
+
+// Character n-grams of all the tokens with their frequency
+pub fn count_char_ngrams<'t>(
+    tokens: impl IntoIterator<Item = (&'t String, &'t TermFreq)>,
+    n: usize,
+) -> HashMap<String, TermFreq> {
+    let mut ngrams: HashMap<String, TermFreq> = HashMap::new();
+    for (token, freq) in tokens {
+        for gram in char_ngrams(token, n) {
+            *ngrams.entry(gram).or_insert(0) += freq;
+        }
+    }
+    ngrams
+}
+
+// Soundex code of a word, e.g. both "Smith" and "Smyth" are S530
+pub fn soundex(word: &str) -> String {
+    let digit = |c: char| match c {
+        'b' | 'f' | 'p' | 'v' => Some('1'),
+        'c' | 'g' | 'j' | 'k' | 'q' | 's' | 'x' | 'z' => Some('2'),
+        'd' | 't' => Some('3'),
+        'l' => Some('4'),
+        'm' | 'n' => Some('5'),
+        'r' => Some('6'),
+        _ => None,
+    };
+    let mut letters = word
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_lowercase());
+    let Some(first) = letters.next() else {
+        return String::new();
+    };
+    let mut code = String::from(first.to_ascii_uppercase());
+    let mut last = digit(first);
+    for c in letters {
+        let current = digit(c);
+        if let Some(d) = current {
+            if current != last {
+                code.push(d);
+            }
+        }
+        // letters coded the same separated by h or w are only coded once
+        if c != 'h' && c != 'w' {
+            last = current;
+        }
+        if code.len() == 4 {
+            break;
+        }
+    }
+    while code.len() < 4 {
+        code.push('0');
+    }
+    code
+}
+
+pub fn load_stopwords() -> Result<HashSet<String>, std::io::Error> {
+    load_stopwords_from("scifact/stopwords.txt")
+}
+
+pub fn load_stopwords_from(file_path: &str) -> Result<HashSet<String>, std::io::Error> {
+    let file = File::open(file_path)?;
     BufReader::new(file)
         .lines()
-        .map(|line| line.unwrap())
+        .map(|line| line.map(|l| l.to_lowercase()))
         .collect()
 }
 
-pub fn process_queries(queries: Vec<Query>) -> Vec<TokenizedQuery> {
-    //extract words, remove stopwords, stem
-    let mut tokenized: Vec<TokenizedQuery> = vec![];
-    let stopwords = load_stopwords();
-    for query in queries {
-        let mut words = extract_words(&query.text);
-        remove_stopwords(&mut words, &stopwords);
-        let mut stemmed_words = stem_words(words);
-        stemmed_words.retain(|w| w.len() > 1); // remove words that ended up being 2 letter or less
-        let mut frequency: HashMap<String, u16> = HashMap::new();
-        for word in stemmed_words {
-            *frequency.entry(word).or_insert(0) += 1;
-        }
-        tokenized.push(TokenizedQuery {
-            _id: query._id,
-            tokens: frequency,
-            metadata: query.metadata,
+// One free text query per line, numbered from 1 in file order. Blank lines are skipped
+pub fn load_plain_queries(file_path: &str) -> Result<Vec<Query>, std::io::Error> {
+    let file = File::open(file_path)?;
+    let mut queries: Vec<Query> = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        queries.push(Query {
+            _id: (queries.len() + 1).to_string(),
+            text: line,
+            metadata: HashMap::new(),
         });
     }
+    Ok(queries)
+}
+
+// Extracts the `word^boost` markers from the query text, keyed by the token of the word
+pub fn extract_boosts(str: &str) -> HashMap<String, f32> {
+    extract_boosts_with_config(str, &HashSet::new(), &DEFAULT_CONFIG)
+}
+
+// The marked words go through the same pipeline as the query text so the keys match its tokens
+pub fn extract_boosts_with_config(
+    str: &str,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> HashMap<String, f32> {
+    let mut boosts: HashMap<String, f32> = HashMap::new();
+    for cap in BOOST_REGEX.captures_iter(str) {
+        let boost = cap[2].parse::<f32>().unwrap_or(1.0);
+        for token in tokenize_text_with_config(&cap[1], stopwords, config) {
+            boosts.insert(token, boost);
+        }
+    }
+    boosts
+}
+
+// Extracts the tokens of the `-word` markers from the query text
+pub fn extract_excluded(str: &str) -> HashSet<String> {
+    extract_excluded_with_config(str, &HashSet::new(), &DEFAULT_CONFIG)
+}
+
+pub fn extract_excluded_with_config(
+    str: &str,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> HashSet<String> {
+    EXCLUDE_REGEX
+        .captures_iter(str)
+        .flat_map(|cap| tokenize_text_with_config(&cap[1], stopwords, config))
+        .collect()
+}
+
+// The query id is parsed once here, a query whose id isn't a u32 is an error
+pub fn process_query(
+    query: Query,
+    stopwords: &HashSet<String>,
+) -> Result<TokenizedQuery, ParseIntError> {
+    process_query_with_config(query, stopwords, &DEFAULT_CONFIG)
+}
+
+pub fn process_query_with_config(
+    query: Query,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> Result<TokenizedQuery, ParseIntError> {
+    let fingerprint = PreprocessingFingerprint::new(stopwords, config);
+    let query_id = query._id.parse::<u32>()?;
+    Ok(process_query_fingerprinted(
+        query_id,
+        query,
+        stopwords,
+        config,
+        fingerprint,
+    ))
+}
+
+// Tokenizes free text that isn't part of a query file, the query gets the id 0
+pub fn process_query_text(
+    text: &str,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> TokenizedQuery {
+    let query = Query {
+        _id: String::from("0"),
+        text: text.to_string(),
+        metadata: HashMap::new(),
+    };
+    let fingerprint = PreprocessingFingerprint::new(stopwords, config);
+    process_query_fingerprinted(0, query, stopwords, config, fingerprint)
+}
+
+fn process_query_fingerprinted(
+    query_id: u32,
+    query: Query,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+    fingerprint: PreprocessingFingerprint,
+) -> TokenizedQuery {
+    let boosts = extract_boosts_with_config(&query.text, stopwords, config);
+    let excluded = extract_excluded_with_config(&query.text, stopwords, config);
+    // an excluded word is a filter, it doesn't also count towards the score
+    let tokens: Vec<String> = tokenize_text_with_config(&query.text, stopwords, config)
+        .into_iter()
+        .filter(|word| !excluded.contains(word))
+        .collect();
+    let bigrams = count_bigrams(&tokens);
+    let mut frequency: HashMap<String, TermFreq> = HashMap::new();
+    for word in tokens {
+        *frequency.entry(word).or_insert(0) += 1;
+    }
+    TokenizedQuery {
+        _id: query_id,
+        tokens: frequency,
+        metadata: query.metadata,
+        boosts,
+        bigrams,
+        excluded,
+        fingerprint: Some(fingerprint),
+    }
+}
+
+pub fn process_queries(queries: Vec<Query>) -> Result<Vec<TokenizedQuery>, SetupError> {
+    Ok(process_queries_with(
+        queries,
+        &load_stopwords()?,
+        &DEFAULT_CONFIG,
+    )?)
+}
+
+// config must be the IndexConfig.preprocess the documents were indexed with
+pub fn process_queries_with(
+    queries: Vec<Query>,
+    stopwords: &HashSet<String>,
+    config: &PreprocessConfig,
+) -> Result<Vec<TokenizedQuery>, ParseIntError> {
+    //extract words, remove stopwords, stem
+    // each query is tokenized on its own thread, collect keeps the input order
+    let fingerprint = PreprocessingFingerprint::new(stopwords, config);
+    queries
+        .into_par_iter()
+        .map(|query| {
+            let query_id = query._id.parse::<u32>()?;
+            Ok(process_query_fingerprinted(
+                query_id,
+                query,
+                stopwords,
+                config,
+                fingerprint,
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: &str, text: &str) -> Query {
+        Query {
+            _id: id.to_string(),
+            text: text.to_string(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    fn stopwords() -> HashSet<String> {
+        ["the", "of", "in", "a", "and"]
+            .iter()
+            .map(|w| w.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parallel_queries_match_sequential() {
+        let texts = [
+            "the role of proteins in cell growth",
+            "blood pressure and heart disease",
+            "gene expression in tumor cells",
+            "cells cells cells",
+        ];
+        let queries = texts
+            .iter()
+            .enumerate()
+            .map(|(n, text)| query(&n.to_string(), text))
+            .collect();
+        let parallel =
+            process_queries_with(queries, &stopwords(), &PreprocessConfig::default()).unwrap();
+        assert_eq!(parallel.len(), texts.len());
+        for (n, (text, tokenized)) in texts.iter().zip(&parallel).enumerate() {
+            let sequential = process_query(query(&n.to_string(), text), &stopwords()).unwrap();
+            assert_eq!(tokenized._id, sequential._id);
+            assert_eq!(tokenized.tokens, sequential.tokens);
+            assert_eq!(tokenized.bigrams, sequential.bigrams);
+            assert_eq!(tokenized.fingerprint, sequential.fingerprint);
+        }
+    }
+
+    #[test]
+    fn capitalized_stopwords_are_removed() {
+        let stopwords = stopwords();
+        let tokens = tokenize_text("The cells And THE proteins", &stopwords);
+        assert_eq!(tokens, tokenize_text("cells proteins", &stopwords));
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn allowlisted_punctuation_keeps_the_token() {
+        let strict = tokenize_text("t-cell activation", &stopwords());
+        assert!(strict.iter().all(|token| !token.contains('-')));
+        let config = PreprocessConfig::default().with_allowed_punctuation(&['-']);
+        let tokens = tokenize_text_with_config("t-cell activation", &stopwords(), &config);
+        assert!(tokens[0].starts_with("t-cel"));
+        assert_eq!(tokens[1..], strict[strict.len() - 1..]);
+        // a lone allowlisted character isn't a token
+        assert_eq!(
+            tokenize_text_with_config("cell - cell", &stopwords(), &config).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn cached_stems_match_uncached_stems() {
+        let words = vec![
+            "running",
+            "cells",
+            "running",
+            "proteins",
+            "cells",
+            "running",
+            "expression",
+        ];
+        let cached = PreprocessConfig::default().with_stem_cache(true);
+        let uncached = PreprocessConfig::default().with_stem_cache(false);
+        let expected = stem_words_with_config(words.clone(), &uncached);
+        // the second pass is served from the cache
+        assert_eq!(stem_words_with_config(words.clone(), &cached), expected);
+        assert_eq!(stem_words_with_config(words, &cached), expected);
+    }
+
+    #[test]
+    fn empty_stopword_set_removes_nothing() {
+        let queries = vec![query("1", "the role of cells")];
+        let tokenized =
+            process_queries_with(queries, &HashSet::new(), &PreprocessConfig::default()).unwrap();
+        let with_stopwords = tokenize_text("the role of cells", &stopwords());
+        assert_eq!(tokenized[0].tokens.len(), 4);
+        assert!(tokenized[0].tokens.contains_key("the"));
+        assert!(tokenized[0].tokens.contains_key("of"));
+        assert_eq!(with_stopwords.len(), 2);
+    }
+
+    #[test]
+    fn positions_skip_filtered_words() {
+        let words = extract_words_with_positions("Cells, 42 proteins (and) genes!");
+        assert_eq!(
+            words,
+            vec![(0, "Cells"), (1, "proteins"), (2, "and"), (3, "genes")]
+        );
+        assert_eq!(
+            extract_words("Cells, 42 proteins (and) genes!"),
+            vec!["Cells", "proteins", "and", "genes"]
+        );
+    }
+
+    #[test]
+    fn custom_word_regex_changes_the_tokens() {
+        let text = "cancer_cells growth";
+        let default = tokenize_text(text, &stopwords());
+        // \w+ keeps the underscore in the word, which is then dropped for its punctuation,
+        // [a-z]+ splits the word on it instead
+        let config = PreprocessConfig::default()
+            .with_word_regex(r"[a-z]+")
+            .unwrap();
+        let custom = tokenize_text_with_config(text, &stopwords(), &config);
+        assert_eq!(default, tokenize_text("growth", &stopwords()));
+        assert_eq!(custom, tokenize_text("cancer cells growth", &stopwords()));
+        assert!(PreprocessConfig::default()
+            .with_word_regex(r"(unclosed")
+            .is_err());
+    }
+
+    #[test]
+    fn plain_queries_are_numbered_from_one() {
+        let path =
+            std::env::temp_dir().join(format!("assignment1_{}_queries.txt", std::process::id()));
+        std::fs::write(&path, "cancer cells\n\nblood pressure\ngene expression\n").unwrap();
+        let queries = load_plain_queries(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let tokenized =
+            process_queries_with(queries, &stopwords(), &PreprocessConfig::default()).unwrap();
+        let ids: Vec<u32> = tokenized.iter().map(|query| query._id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(tokenized[1].tokens.len(), 2);
+        assert!(tokenized.iter().all(|query| query.metadata.is_empty()));
+    }
+
+    #[test]
+    fn frequencies_above_u16_do_not_wrap() {
+        let text = "cells ".repeat(70_000);
+        let frequency = preprocess_text(text, &stopwords());
+        assert_eq!(frequency.values().copied().collect::<Vec<_>>(), [70_000]);
+        let document = TokenizedDocument::new(1, HashMap::new(), frequency);
+        assert_eq!(document.tokens().values().sum::<TermFreq>(), 70_000);
+    }
+
+    #[test]
+    fn non_numeric_query_ids_are_an_error() {
+        let config = PreprocessConfig::default();
+        let queries = vec![query("1", "cell growth"), query("q2", "blood")];
+        assert!(process_queries_with(queries, &stopwords(), &config).is_err());
+        let tokenized = process_query(query("12", "cell growth"), &stopwords()).unwrap();
+        // the id is saved as a string like in the query files
+        let json = serde_json::to_string(&tokenized).unwrap();
+        assert!(json.starts_with(r#"{"_id":"12","#));
+        let loaded: TokenizedQuery = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded._id, 12);
+    }
+
+    #[test]
+    fn failed_stems_are_left_out() {
+        // the stemmer only handles ASCII words
+        let (stems, failures) = stem_words_with_failures(vec!["cells", "naïve", "genes"]);
+        assert_eq!(stems, stem_words(vec!["cells", "genes"]));
+        assert_eq!(failures, ["naïve"]);
+        assert!(stems.iter().all(|stem| !stem.is_empty()));
+    }
 
-    return tokenized;
+    #[test]
+    fn lowercasing_first_gives_case_invariant_stems() {
+        let config = PreprocessConfig::default().with_lowercase_before_stemming(true);
+        let capitalized = tokenize_text_with_config("Running", &stopwords(), &config);
+        let lowercase = tokenize_text_with_config("running", &stopwords(), &config);
+        assert_eq!(capitalized, lowercase);
+        assert_eq!(lowercase, ["run"]);
+    }
+
+    #[test]
+    fn different_stopwords_are_a_fingerprint_mismatch() {
+        let config = PreprocessConfig::default();
+        let index_fingerprint = PreprocessingFingerprint::new(&stopwords(), &config);
+        let mut other_stopwords = stopwords();
+        other_stopwords.insert("cell".to_string());
+        let queries = process_queries_with(
+            vec![query("1", "the cell cycle")],
+            &other_stopwords,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(
+            verify_queries(&index_fingerprint, &queries),
+            Err(FingerprintMismatch {
+                index: index_fingerprint,
+                query: PreprocessingFingerprint::new(&other_stopwords, &config),
+            })
+        );
+        let queries =
+            process_queries_with(vec![query("1", "the cell cycle")], &stopwords(), &config)
+                .unwrap();
+        assert_eq!(verify_queries(&index_fingerprint, &queries), Ok(()));
+    }
+
+    #[test]
+    fn inflected_stopwords_are_removed_after_stemming() {
+        let stopwords: HashSet<String> = ["have".to_string()].into();
+        let text = "having cells";
+        // having isn't in the list, so it passes the filter before stemming
+        let plain = tokenize_text_with_config(text, &stopwords, &PreprocessConfig::default());
+        assert_eq!(plain, ["have", "cell"]);
+        let config = PreprocessConfig::default().with_stemmed_stopwords(&stopwords);
+        let filtered = tokenize_text_with_config(text, &stopwords, &config);
+        assert_eq!(filtered, ["cell"]);
+    }
+
+    #[test]
+    fn id_and_contents_keys_are_read() {
+        let document: Document =
+            serde_json::from_str(r#"{"id": "42", "contents": "Cells divide"}"#).unwrap();
+        assert_eq!(document._id, "42");
+        assert_eq!(document.title, "");
+        assert_eq!(document.text, "Cells divide");
+        let query: Query =
+            serde_json::from_str(r#"{"id": "3", "contents": "cell growth"}"#).unwrap();
+        assert_eq!(
+            (query._id.as_str(), query.text.as_str()),
+            ("3", "cell growth")
+        );
+        assert!(query.metadata.is_empty());
+    }
+
+    #[test]
+    fn protected_words_are_not_stemmed() {
+        let protected: HashSet<String> = ["AIDS".to_string()].into();
+        let config = PreprocessConfig::default().with_protected_words(&protected);
+        let text = "Aids patients";
+        assert_eq!(
+            tokenize_text_with_config(text, &stopwords(), &PreprocessConfig::default()),
+            ["aid", "patient"]
+        );
+        assert_eq!(
+            tokenize_text_with_config(text, &stopwords(), &config),
+            ["aids", "patient"]
+        );
+        // documents and queries go through the same tokenizer
+        let processed =
+            process_queries_with(vec![query("1", text)], &stopwords(), &config).unwrap();
+        assert_eq!(processed[0].tokens.get("aids"), Some(&1));
+        assert_eq!(processed[0].tokens.get("patient"), Some(&1));
+    }
+
+    #[test]
+    fn boost_markers_follow_the_query_pipeline() {
+        let stopwords: HashSet<String> = ["have".to_string()].into();
+        let config = PreprocessConfig::default()
+            .with_lowercase_before_stemming(true)
+            .with_stemmed_stopwords(&stopwords);
+        let q =
+            process_query_with_config(query("1", "Running^2 having^3 cells"), &stopwords, &config)
+                .unwrap();
+        assert_eq!(q.boosts, HashMap::from([("run".to_string(), 2.0)]));
+        assert!(q.boosts.keys().all(|term| q.tokens.contains_key(term)));
+    }
+
+    #[test]
+    fn exclude_markers_follow_the_query_pipeline() {
+        let stopwords: HashSet<String> = ["have".to_string()].into();
+        let config = PreprocessConfig::default()
+            .with_lowercase_before_stemming(true)
+            .with_stemmed_stopwords(&stopwords);
+        let q =
+            process_query_with_config(query("1", "cells -Running -having"), &stopwords, &config)
+                .unwrap();
+        assert_eq!(q.excluded, HashSet::from(["run".to_string()]));
+        assert_eq!(q.tokens, HashMap::from([("cell".to_string(), 1)]));
+    }
 }
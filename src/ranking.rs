@@ -1,10 +1,87 @@
 use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet},
     fs::File,
-    io::Write,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex, OnceLock},
 };
 
-use crate::{indexing::InvertedIndex, preprocessing::TokenizedQuery};
+use crate::{
+    indexing::{
+        FieldIndexes, FieldLengths, ImpactIndex, InvertedIndex, PhoneticIndex, PositionalIndex,
+    },
+    preprocessing::{
+        count_char_ngrams, process_query_text, soundex, FingerprintMismatch, PreprocessConfig,
+        PreprocessingFingerprint, TermFreq, TokenizedQuery,
+    },
+};
+
+// Number of results kept per query unless top_k is changed
+pub const DEFAULT_TOP_K: usize = 100;
+
+// Number of documents of each query logged by the trace feature
+#[cfg(feature = "trace")]
+const TRACE_TOP_DOCS: usize = 5;
+
+// s and o of the bm25 idf variants, see IdfVariant
+pub const DEFAULT_IDF_SMOOTHING: f32 = 0.5;
+pub const DEFAULT_IDF_OFFSET: f32 = 1.0;
+
+// Decimal places of the scores in the results file
+pub const DEFAULT_SCORE_PRECISION: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdfVariant {
+    // ln((N - df + s) / (df + s)), goes negative for terms in more than half the documents
+    Bm25Probabilistic,
+    // ln(N / df)
+    Standard,
+    // ln((N - df + s) / (df + s) + o), Lucene's +1 offset (o = 1.0) keeps it non-negative
+    Bm25Plus1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoringModel {
+    // Cosine similarity between the bm25 weighted document and the idf weighted query
+    Cosine,
+    // Sum of the bm25 weights of the query terms
+    Bm25,
+    // Query likelihood with Jelinek-Mercer smoothing, lambda is the weight of the collection model
+    LmJelinekMercer { lambda: f32 },
+    // Query likelihood with a Dirichlet prior, mu is usually in the thousands (less for abstracts)
+    LmDirichlet { mu: f32 },
+}
+
+// How the frequency of a query term counts in its cosine query weight
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QueryTfScheme {
+    // freq
+    #[default]
+    Raw,
+    // 1 + ln(freq), dampens repeated terms
+    Log,
+    // 1 for any term in the query
+    Boolean,
+}
+
+impl QueryTfScheme {
+    pub fn weight(&self, freq: TermFreq) -> f32 {
+        match (self, freq) {
+            (_, 0) => 0.0,
+            (QueryTfScheme::Raw, freq) => freq as f32,
+            (QueryTfScheme::Log, freq) => 1.0 + (freq as f32).ln(),
+            (QueryTfScheme::Boolean, _) => 1.0,
+        }
+    }
+}
+
+// Query side of the cosine similarity, the same for every document
+pub struct QueryVector<'q> {
+    pub weights: Vec<(&'q str, f32)>,
+    pub length: f32,
+}
 
 pub struct Ranking<'a> {
     pub k1: f32,
@@ -13,181 +90,3537 @@ pub struct Ranking<'a> {
     pub num_doc: u32,
     pub inv_index: &'a InvertedIndex,
     pub doc_lengths: &'a HashMap<u32, u32>,
+    pub idf_variant: IdfVariant,
+    // Added to df and N - df by the bm25 idf variants, a larger value flattens the idf of rare terms
+    pub idf_smoothing: f32,
+    // Added inside the log by Bm25Plus1, 0 makes it Bm25Probabilistic
+    pub idf_offset: f32,
+    pub model: ScoringModel,
+    pub query_tf_scheme: QueryTfScheme,
+    // Weight of the proximity bonus added to the score, 0 disables it
+    pub proximity_weight: f32,
+    pub positional_index: Option<&'a PositionalIndex>,
+    // When set, query terms missing from the index are matched on their soundex code
+    pub phonetic_index: Option<&'a PhoneticIndex>,
+    // Bigram index blended into the score with bigram_weight, 0 disables it
+    pub bigram_index: Option<&'a InvertedIndex>,
+    pub bigram_weight: f32,
+    // When set, only the pooled documents of a query are scored
+    // {query_id: {doc_id, ...}}
+    pub candidate_pool: Option<&'a HashMap<u32, HashSet<u32>>>,
+    // Number of results kept per query
+    pub top_k: usize,
+    // {query_id: top_k}, queries without an entry keep top_k results
+    pub top_k_overrides: Option<&'a HashMap<u32, usize>>,
+    // Multiplies the score by the fraction of the query terms in the document
+    pub coord: bool,
+    // When set, only the n query terms with the highest idf are scored
+    pub keep_top_idf: Option<usize>,
+    // Rebuilt whenever the index is replaced
+    index_stats: Arc<IndexStats>,
+    // Per field indexes and lengths for field normalized bm25, set with set_fields
+    field_indexes: Option<&'a FieldIndexes>,
+    field_lengths: Option<&'a FieldLengths>,
+    // {field: average length}, sorted so the per field sums add up in the same order
+    field_avgdl: BTreeMap<String, f32>,
+    // Results of the queries already ranked, see enable_query_cache
+    query_cache: Option<Mutex<QueryCache>>,
+    // {field: weight} of the per field bm25 weights summed by bm25_weight once set_fields is
+    // called, every field has a weight of 1.0 when empty
+    pub field_weights: HashMap<String, f32>,
+    // Fingerprint saved with the index, search rejects a config preprocessing differently
+    pub index_fingerprint: Option<PreprocessingFingerprint>,
 }
 
 impl<'a> Ranking<'a> {
+    // Out of range parameters are clamped with a warning, use try_init to reject them instead
     pub fn init(
         doc_lengths: &'a HashMap<u32, u32>,
         inverted_index: &'a InvertedIndex,
         k1: f32,
         b: f32,
     ) -> Ranking<'a> {
-        let num_doc = doc_lengths.len() as u32;
-        let avgdl = doc_lengths.clone().into_values().sum::<u32>() / num_doc;
+        let (k1, b) = match validate_parameters(k1, b) {
+            Ok(()) => (k1, b),
+            Err(e) => {
+                // max and the NaN check also replace NaN parameters by 0
+                let b = if b.is_nan() { 0.0 } else { b.clamp(0.0, 1.0) };
+                let clamped = (k1.max(0.0), b);
+                eprintln!("Warning: {}, using k1={} b={}", e, clamped.0, clamped.1);
+                clamped
+            }
+        };
+        Ranking::build(doc_lengths, inverted_index, k1, b)
+    }
+
+    pub fn try_init(
+        doc_lengths: &'a HashMap<u32, u32>,
+        inverted_index: &'a InvertedIndex,
+        k1: f32,
+        b: f32,
+    ) -> Result<Ranking<'a>, ParameterError> {
+        validate_parameters(k1, b)?;
+        Ok(Ranking::build(doc_lengths, inverted_index, k1, b))
+    }
+
+    fn build(
+        doc_lengths: &'a HashMap<u32, u32>,
+        inverted_index: &'a InvertedIndex,
+        k1: f32,
+        b: f32,
+    ) -> Ranking<'a> {
+        let index_stats = Arc::new(IndexStats::build(inverted_index));
+        let mut ranking =
+            Ranking::build_with_stats(doc_lengths, inverted_index, k1, b, index_stats);
+        ranking.update_stats();
+        ranking
+    }
 
+    // num_doc and avgdl are left at 0, see update_stats
+    fn build_with_stats(
+        doc_lengths: &'a HashMap<u32, u32>,
+        inverted_index: &'a InvertedIndex,
+        k1: f32,
+        b: f32,
+        index_stats: Arc<IndexStats>,
+    ) -> Ranking<'a> {
         Ranking {
             k1,
             b,
-            avgdl,
-            num_doc,
+            avgdl: 0,
+            num_doc: 0,
             inv_index: inverted_index,
             doc_lengths,
+            idf_variant: IdfVariant::Bm25Plus1,
+            idf_smoothing: DEFAULT_IDF_SMOOTHING,
+            idf_offset: DEFAULT_IDF_OFFSET,
+            model: ScoringModel::Cosine,
+            query_tf_scheme: QueryTfScheme::default(),
+            proximity_weight: 0.0,
+            positional_index: None,
+            phonetic_index: None,
+            bigram_index: None,
+            bigram_weight: 0.0,
+            candidate_pool: None,
+            top_k: DEFAULT_TOP_K,
+            top_k_overrides: None,
+            coord: false,
+            keep_top_idf: None,
+            index_stats,
+            field_indexes: None,
+            field_lengths: None,
+            field_avgdl: BTreeMap::new(),
+            field_weights: HashMap::new(),
+            query_cache: None,
+            index_fingerprint: None,
+        }
+    }
+
+    // Recomputes num_doc and avgdl from doc_lengths
+    pub fn update_stats(&mut self) {
+        self.num_doc = self.doc_lengths.len() as u32;
+        self.avgdl = match self.num_doc {
+            0 => 0,
+            n => self.doc_lengths.values().sum::<u32>() / n,
+        };
+    }
+
+    // Replaces the index and document lengths after documents were added or removed
+    pub fn set_documents(
+        &mut self,
+        inverted_index: &'a InvertedIndex,
+        doc_lengths: &'a HashMap<u32, u32>,
+    ) {
+        self.doc_lengths = doc_lengths;
+        self.set_index(inverted_index);
+        self.update_stats();
+    }
+
+    // Keeps the results of the last `capacity` distinct queries given to rank_query, evicting the
+    // least recently used. The cache is cleared when the index changes, but not when the public
+    // fields do, so enable it once the ranking is configured
+    pub fn enable_query_cache(&mut self, capacity: usize) {
+        self.query_cache = Some(Mutex::new(QueryCache::new(capacity)));
+    }
+
+    // (hits, misses) of the query cache, None when it isn't enabled
+    pub fn query_cache_stats(&self) -> Option<(u64, u64)> {
+        let cache = self.query_cache.as_ref()?.lock().unwrap();
+        Some((cache.hits, cache.misses))
+    }
+
+    fn clear_query_cache(&mut self) {
+        if let Some(cache) = &self.query_cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    // Replaces the inverted index, keeping the document frequencies in sync
+    pub fn set_index(&mut self, inverted_index: &'a InvertedIndex) {
+        self.clear_query_cache();
+        self.inv_index = inverted_index;
+        self.index_stats = Arc::new(IndexStats::build(inverted_index));
+    }
+
+    // Sets the per field indexes and lengths, computing the average length of each field
+    pub fn set_fields(&mut self, field_indexes: &'a FieldIndexes, field_lengths: &'a FieldLengths) {
+        self.clear_query_cache();
+        self.field_indexes = Some(field_indexes);
+        self.field_lengths = Some(field_lengths);
+        self.field_avgdl = average_field_lengths(field_lengths);
+    }
+
+    pub fn field_avgdl(&self, field: &str) -> f32 {
+        *self.field_avgdl.get(field).unwrap_or(&0.0)
+    }
+
+    // bm25 weight of the term in one field, normalized by that field's length and average length.
+    // The idf is the one of the combined index.
+    pub fn field_bm25_weight(&self, field: &str, doc_id: &u32, term: &str) -> f32 {
+        let (Some(field_indexes), Some(field_lengths)) = (self.field_indexes, self.field_lengths)
+        else {
+            return 0.0;
+        };
+        let Some(&tf) = field_indexes
+            .get(field)
+            .and_then(|index| index.get(term))
+            .and_then(|doc_map| doc_map.get(doc_id))
+        else {
+            return 0.0;
+        };
+        let length = field_lengths
+            .get(field)
+            .and_then(|lengths| lengths.get(doc_id))
+            .map_or(0, |length| *length);
+        let avgdl = self.field_avgdl(field);
+        if length == 0 || avgdl <= 0.0 {
+            return 0.0;
+        }
+        self.idf(term) * tf as f32
+            / (self.k1 * ((1.0 - self.b) + self.b * (length as f32 / avgdl)) + tf as f32)
+    }
+
+    // Frequency of the term in the document
+    pub fn tf(&self, doc_id: &u32, term: &str) -> TermFreq {
+        self.inv_index
+            .get(term)
+            .and_then(|doc_map| doc_map.get(doc_id))
+            .map_or(0, |tf| *tf)
+    }
+
+    pub fn doc_length(&self, doc_id: &u32) -> u32 {
+        *self.doc_lengths.get(doc_id).unwrap_or(&0)
+    }
+
+    pub fn vocabulary_size(&self) -> usize {
+        self.inv_index.len()
+    }
+
+    pub fn document_count(&self) -> u32 {
+        self.num_doc
+    }
+
+    // Exact mean of doc_lengths, the avgdl used for scoring is rounded down
+    pub fn average_doc_length(&self) -> f32 {
+        match self.num_doc {
+            0 => 0.0,
+            n => {
+                self.doc_lengths
+                    .values()
+                    .map(|length| *length as f32)
+                    .sum::<f32>()
+                    / n as f32
+            }
         }
     }
+
+    // Total occurrences of the term across all the documents
+    pub fn collection_freq(&self, term: &str) -> u64 {
+        *self.index_stats.cf.get(term).unwrap_or(&0)
+    }
+
+    // Sum of the collection frequencies of every term
+    pub fn total_tokens(&self) -> u64 {
+        self.index_stats.total_tokens
+    }
+
+    pub fn df(&self, term: &str) -> usize {
+        *self.index_stats.df.get(term).unwrap_or(&0)
+    }
+
+    // Lazily yields every token of the index with its document frequency
+    pub fn vocabulary_with_df(&self) -> impl Iterator<Item = (&str, usize)> + '_ {
+        self.inv_index
+            .iter()
+            .map(|(term, doc_map)| (term.as_str(), doc_map.len()))
+    }
+
+    // Query terms missing from the vocabulary, sorted
+    pub fn query_oov_terms(&self, query: &TokenizedQuery) -> Vec<String> {
+        let mut oov: Vec<String> = query
+            .tokens
+            .keys()
+            .filter(|term| !self.inv_index.contains_key(*term))
+            .cloned()
+            .collect();
+        oov.sort();
+        oov
+    }
+
+    // {query_id: out of vocabulary terms} for the queries with at least one
+    pub fn batch_oov_terms(&self, queries: &[TokenizedQuery]) -> BTreeMap<u32, Vec<String>> {
+        queries
+            .iter()
+            .map(|query| (query._id, self.query_oov_terms(query)))
+            .filter(|(_, oov)| !oov.is_empty())
+            .collect()
+    }
+
     pub fn idf(&self, term: &str) -> f32 {
+        self.idf_from_df(self.df(term))
+    }
+
+    pub fn idf_from_df(&self, df: usize) -> f32 {
         // if inv_index doesnt contain term, idf is 0
-        let df = self.inv_index.get(term).map_or(0, |map| map.len());
         if df == 0 {
             return 0.0;
         }
-        return ((self.num_doc as f32 - df as f32 + 0.5) / (df as f32 + 0.5) + 1.0).ln();
+        let (n, df) = (self.num_doc as f32, df as f32);
+        let s = self.idf_smoothing;
+        match self.idf_variant {
+            IdfVariant::Bm25Probabilistic => ((n - df + s) / (df + s)).ln(),
+            IdfVariant::Standard => (n / df).ln(),
+            IdfVariant::Bm25Plus1 => ((n - df + s) / (df + s) + self.idf_offset).ln(),
+        }
     }
 
+    // With field indexes (see set_fields) each field is normalized by its own length
     pub fn bm25_weight(&self, doc_id: &u32, term: &str) -> f32 {
-        let doc_length = *self.doc_lengths.get(doc_id).unwrap_or(&0);
-        if let Some(term_map) = self.inv_index.get(term) {
+        match self.field_indexes {
+            Some(_) => self.fields_bm25_weight(doc_id, term),
+            None => self.bm25_weight_in(self.inv_index, self.df(term), doc_id, term),
+        }
+    }
+
+    // Sum of the weighted field_bm25_weight of the term over the fields
+    fn fields_bm25_weight(&self, doc_id: &u32, term: &str) -> f32 {
+        self.field_avgdl
+            .keys()
+            .map(|field| {
+                let weight = match self.field_weights.is_empty() {
+                    true => 1.0,
+                    false => *self.field_weights.get(field).unwrap_or(&0.0),
+                };
+                weight as f64 * self.field_bm25_weight(field, doc_id, term) as f64
+            })
+            .sum::<f64>() as f32
+    }
+
+    // bm25 weight of a term in the given index, df is the term's document frequency in that index
+    fn bm25_weight_in(&self, index: &InvertedIndex, df: usize, doc_id: &u32, term: &str) -> f32 {
+        // documents without tokens (or without a known length) don't get a weight
+        let doc_length = match self.doc_lengths.get(doc_id) {
+            Some(&length) if length > 0 && self.avgdl > 0 => length,
+            _ => return 0.0,
+        };
+        if let Some(term_map) = index.get(term) {
             if let Some(&tf) = term_map.get(doc_id) {
-                let idf = self.idf(term);
-                return idf * tf as f32
-                    / (self.k1
-                        * ((1.0 - self.b) + self.b * (doc_length as f32 / self.avgdl as f32))
-                        + tf as f32);
+                return self.bm25_term_weight(self.idf_from_df(df), tf, doc_length);
             }
         }
         0.0
     }
 
+    // The bm25 formula, computed in f64 and rounded once
+    fn bm25_term_weight(&self, idf: f32, tf: TermFreq, doc_length: u32) -> f32 {
+        let (idf, k1, b, tf) = (idf as f64, self.k1 as f64, self.b as f64, tf as f64);
+        let weight =
+            idf * tf / (k1 * ((1.0 - b) + b * (doc_length as f64 / self.avgdl as f64)) + tf);
+        weight as f32
+    }
+
+    // Bound on the bm25 weight of the term in any of its documents, from the highest tf and the
+    // shortest length of its posting list (the lowest tf and longest length if the idf is negative)
+    pub fn term_upper_bound(&self, term: &str) -> f32 {
+        let Some(term_map) = self.inv_index.get(term) else {
+            return 0.0;
+        };
+        if self.avgdl == 0 {
+            return 0.0;
+        }
+        // the field lengths aren't bounded by the merged lengths, take the highest actual weight
+        if self.field_indexes.is_some() {
+            return term_map
+                .keys()
+                .map(|doc_id| self.bm25_weight(doc_id, term))
+                .fold(0.0, f32::max);
+        }
+        let idf = self.idf(term);
+        // documents without a length get a weight of 0
+        let postings: Vec<(TermFreq, u32)> = term_map
+            .iter()
+            .filter_map(|(doc_id, tf)| match self.doc_lengths.get(doc_id) {
+                Some(&length) if length > 0 => Some((*tf, length)),
+                _ => None,
+            })
+            .collect();
+        let (tf, doc_length) = match idf >= 0.0 {
+            true => (
+                postings.iter().map(|(tf, _)| *tf).max(),
+                postings.iter().map(|(_, length)| *length).min(),
+            ),
+            false => (
+                postings.iter().map(|(tf, _)| *tf).min(),
+                postings.iter().map(|(_, length)| *length).max(),
+            ),
+        };
+        let (Some(tf), Some(doc_length)) = (tf, doc_length) else {
+            return 0.0;
+        };
+        let bound = self.bm25_term_weight(idf, tf, doc_length);
+        // documents without a length score 0, which may be above a negative bound
+        match postings.len() < term_map.len() {
+            true => bound.max(0.0),
+            false => bound,
+        }
+    }
+
+    // Sum of the bm25 weights of the query bigrams in the document, 0 without a bigram index
+    pub fn bigram_score(&self, doc_id: &u32, query: &TokenizedQuery) -> f32 {
+        let Some(bigram_index) = self.bigram_index else {
+            return 0.0;
+        };
+        query
+            .bigrams
+            .iter()
+            .map(|(bigram, freq)| {
+                let df = bigram_index.get(bigram).map_or(0, |doc_map| doc_map.len());
+                self.bm25_weight_in(bigram_index, df, doc_id, bigram) as f64 * *freq as f64
+            })
+            .sum::<f64>() as f32
+    }
+
     pub fn vector_length(&self, weights: &[f32]) -> f32 {
         weights
             .iter()
-            .map(|weight| weight.powi(2))
-            .sum::<f32>()
-            .sqrt()
+            .map(|weight| (*weight as f64).powi(2))
+            .sum::<f64>()
+            .sqrt() as f32
     }
 
     pub fn cosine_similarity(&self, doc_id: &u32, query_terms: &TokenizedQuery) -> f32 {
-        let mut sum = 0.0;
-        let mut doc_weights = vec![];
-        let mut q_weights = vec![];
+        self.cosine_with_query_vector(doc_id, &self.query_vector(query_terms))
+    }
 
-        for (term, freq) in &query_terms.tokens {
-            let doc_term_weight = self.bm25_weight(doc_id, term);
-            let query_term_weight = self.idf(term) * (*freq as f32);
+    pub fn query_vector<'q>(&self, query_terms: &'q TokenizedQuery) -> QueryVector<'q> {
+        let mut weights: Vec<(&str, f32)> = query_terms
+            .tokens
+            .iter()
+            .map(|(term, freq)| {
+                let query_term_weight =
+                    self.idf(term) * self.query_tf_scheme.weight(*freq) * query_terms.boost(term);
+                (term.as_str(), query_term_weight)
+            })
+            .collect();
+        // sorted so the sums don't depend on the hash map order
+        weights.sort_by(|a, b| a.0.cmp(b.0));
+        let q_weights: Vec<f32> = weights.iter().map(|(_, weight)| *weight).collect();
+        QueryVector {
+            length: self.vector_length(&q_weights),
+            weights,
+        }
+    }
 
-            sum += query_term_weight * doc_term_weight;
+    // Cosine similarity against a query vector computed once for all the documents of a query.
+    // Summed in f64 and only rounded to f32 at the end, so close documents don't tie on rounding
+    pub fn cosine_with_query_vector(&self, doc_id: &u32, query_vector: &QueryVector) -> f32 {
+        let mut sum = 0.0f64;
+        let mut doc_squares = 0.0f64;
 
-            q_weights.push(query_term_weight);
-            doc_weights.push(doc_term_weight);
+        for (term, query_term_weight) in &query_vector.weights {
+            let doc_term_weight = self.cosine_doc_weight(doc_id, term) as f64;
+            sum += *query_term_weight as f64 * doc_term_weight;
+            doc_squares += doc_term_weight * doc_term_weight;
         }
 
-        let doc_len = self.vector_length(&doc_weights);
-        let q_len = self.vector_length(&q_weights);
+        let doc_len = doc_squares.sqrt();
+        let q_len = query_vector.length as f64;
 
         if doc_len > 0.0 && q_len > 0.0 {
-            sum / (doc_len * q_len)
+            (sum / (doc_len * q_len)) as f32
         } else {
             0.0
         }
     }
 
-    pub fn rank_documents(
+    // Weight of the term in the cosine document vector
+    fn cosine_doc_weight(&self, doc_id: &u32, term: &str) -> f32 {
+        self.bm25_weight(doc_id, term)
+    }
+
+    // Smallest number of consecutive tokens in the document containing every matched query term.
+    // None if the positional index is missing or fewer than 2 query terms are in the document.
+    pub fn min_window_span(&self, doc_id: &u32, query: &TokenizedQuery) -> Option<u32> {
+        let positional_index = self.positional_index?;
+        let term_positions: Vec<&Vec<u32>> = query
+            .tokens
+            .keys()
+            .filter_map(|term| positional_index.get(term)?.get(doc_id))
+            .collect();
+        if term_positions.len() < 2 {
+            return None;
+        }
+        min_window(&term_positions)
+    }
+
+    // Bonus decaying with the span of the query terms, a span equal to the number of matched terms gets the full weight
+    pub fn proximity_bonus(&self, doc_id: &u32, query: &TokenizedQuery) -> f32 {
+        if self.proximity_weight == 0.0 {
+            return 0.0;
+        }
+        let matched = query
+            .tokens
+            .keys()
+            .filter(|term| {
+                self.positional_index
+                    .and_then(|index| index.get(*term))
+                    .is_some_and(|doc_map| doc_map.contains_key(doc_id))
+            })
+            .count();
+        match self.min_window_span(doc_id, query) {
+            Some(span) => self.proximity_weight * matched as f32 / span as f32,
+            None => 0.0,
+        }
+    }
+
+    // Sum of the bm25 weights of the query terms in the document, accumulated in f64
+    pub fn bm25_score(&self, doc_id: &u32, query: &TokenizedQuery) -> f32 {
+        query
+            .tokens
+            .iter()
+            .map(|(term, freq)| {
+                self.bm25_weight(doc_id, term) as f64 * *freq as f64 * query.boost(term) as f64
+            })
+            .sum::<f64>() as f32
+    }
+
+    // Sum over the query terms of log((1 - lambda) * P(t|d) + lambda * P(t|C))
+    pub fn lm_jelinek_mercer_score(
         &self,
-        queries: &[TokenizedQuery],
-    ) -> BTreeMap<u32, BTreeSet<RankingResult>> {
-        let mut results: BTreeMap<u32, BTreeSet<RankingResult>> = BTreeMap::new();
-        const MAX_TREE_SIZE: usize = 100;
-
-        for query in queries.iter() {
-            for term in query.tokens.keys() {
-                if let Some(doc_map) = self.inv_index.get(term) {
-                    for (doc_id, _) in doc_map.iter() {
-                        let q_id = query._id.parse::<u32>().unwrap();
-                        let tag = (doc_id + q_id) % 2_u32.pow(23);
-
-                        let score = self.cosine_similarity(doc_id, query);
-                        let q_entry = results.entry(q_id).or_insert(BTreeSet::new());
-                        q_entry.insert(RankingResult {
-                            query_id: q_id,
-                            doc_id: *doc_id,
-                            score,
-                            tag,
-                        });
-
-                        // Remove the smallest result if the new score is bigger and more than 100 values in tree.
-                        if q_entry.len() > MAX_TREE_SIZE {
-                            q_entry.pop_first();
-                        }
-                    }
-                }
+        doc_id: &u32,
+        query: &TokenizedQuery,
+        lambda: f32,
+    ) -> f32 {
+        self.lm_score(doc_id, query, |tf, doc_length, p_collection| {
+            let p_doc = match doc_length {
+                0 => 0.0,
+                n => tf as f32 / n as f32,
+            };
+            (1.0 - lambda) * p_doc + lambda * p_collection
+        })
+    }
+
+    // Sum over the query terms of log((tf + mu * P(t|C)) / (|d| + mu))
+    pub fn lm_dirichlet_score(&self, doc_id: &u32, query: &TokenizedQuery, mu: f32) -> f32 {
+        self.lm_score(doc_id, query, |tf, doc_length, p_collection| {
+            (tf as f32 + mu * p_collection) / (doc_length as f32 + mu)
+        })
+    }
+
+    // Query log likelihood given the smoothed P(t|d) from (tf, number of tokens of the document,
+    // P(t|C)). The number of tokens isn't doc_lengths, which may count distinct terms for bm25.
+    // Terms absent from the collection are skipped since their probability is 0 everywhere.
+    fn lm_score<F>(&self, doc_id: &u32, query: &TokenizedQuery, smoothed: F) -> f32
+    where
+        F: Fn(TermFreq, u64, f32) -> f32,
+    {
+        let total_tokens = self.index_stats.total_tokens;
+        if total_tokens == 0 {
+            return 0.0;
+        }
+        let doc_length = *self.index_stats.doc_tokens.get(doc_id).unwrap_or(&0);
+        query
+            .tokens
+            .iter()
+            .filter_map(|(term, freq)| {
+                let cf = *self.index_stats.cf.get(term)?;
+                let p_collection = cf as f32 / total_tokens as f32;
+                let tf = self
+                    .inv_index
+                    .get(term)
+                    .and_then(|doc_map| doc_map.get(doc_id))
+                    .map_or(0, |tf| *tf);
+                let likelihood = smoothed(tf, doc_length, p_collection) as f64;
+                Some(likelihood.ln() * *freq as f64 * query.boost(term) as f64)
+            })
+            .sum::<f64>() as f32
+    }
+
+    pub fn build_impact_index(&self) -> ImpactIndex {
+        let mut impact_index: ImpactIndex = HashMap::new();
+        for (term, doc_map) in self.inv_index.iter() {
+            let mut postings: Vec<(u32, f32)> = doc_map
+                .keys()
+                .map(|doc_id| (*doc_id, self.bm25_weight(doc_id, term)))
+                .collect();
+            postings.sort_by(|a, b| b.1.total_cmp(&a.1));
+            impact_index.insert(term.clone(), postings);
+        }
+        impact_index
+    }
+
+    // Top k documents by bm25_score, reading the postings with the highest impact first.
+    // Stops once the best score an unseen document could reach can't enter the top k.
+    pub fn top_k_impact(
+        &self,
+        impact_index: &ImpactIndex,
+        query: &TokenizedQuery,
+        k: usize,
+    ) -> Vec<(u32, f32)> {
+        // (postings, query term weight) for the query terms in the index
+        let lists: Vec<(&Vec<(u32, f32)>, f32)> = query
+            .tokens
+            .iter()
+            .filter_map(|(term, freq)| {
+                impact_index
+                    .get(term)
+                    .map(|postings| (postings, *freq as f32 * query.boost(term)))
+            })
+            .collect();
+        let mut cursors = vec![0; lists.len()];
+        let mut seen: HashSet<u32> = HashSet::new();
+        let mut top: Vec<(u32, f32)> = vec![];
+
+        let impact = |l: usize, cursors: &[usize]| {
+            lists[l]
+                .0
+                .get(cursors[l])
+                .map(|(_, weight)| weight * lists[l].1)
+        };
+
+        loop {
+            let upper_bound: f32 = (0..lists.len()).filter_map(|l| impact(l, &cursors)).sum();
+            if top.len() == k && top.last().is_some_and(|t| t.1 >= upper_bound) {
+                break;
+            }
+            // move forward in the list with the highest remaining impact
+            let next = (0..lists.len())
+                .filter_map(|l| impact(l, &cursors).map(|i| (l, i)))
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            let Some((l, _)) = next else {
+                break;
+            };
+            let doc_id = lists[l].0[cursors[l]].0;
+            cursors[l] += 1;
+
+            if seen.insert(doc_id) {
+                top.push((doc_id, self.bm25_score(&doc_id, query)));
+                top.sort_by(|a, b| b.1.total_cmp(&a.1));
+                top.truncate(k);
             }
         }
+        top
+    }
 
-        return results;
+    // Documents containing a token with the same soundex code as the term
+    pub fn phonetic_docs(&self, term: &str) -> Option<&HashSet<u32>> {
+        self.phonetic_index?.get(&soundex(term))
     }
-}
 
-pub fn save_results_to_file(results: BTreeMap<u32, BTreeSet<RankingResult>>, file_path: &str) {
-    let mut file = File::create(file_path).expect("Failed to create file.");
-    for result in results.iter() {
-        let mut rank = 0;
-        for query_ranking in result.1.iter().rev() {
-            rank += 1;
-            file.write_fmt(format_args!(
-                "{}  {}  {}  {}  {}  {}\n",
-                query_ranking.query_id,
-                "Q0",
-                query_ranking.doc_id,
-                rank,
-                query_ranking.score,
-                query_ranking.tag
-            ))
-            .expect("Failed to write to file.");
+    // Replaces the query terms missing from the index with the index tokens sharing their soundex code
+    pub fn phonetic_expand(&self, query: &TokenizedQuery) -> TokenizedQuery {
+        let mut expanded = query.clone();
+        for (term, freq) in &query.tokens {
+            if self.inv_index.contains_key(term) || self.phonetic_docs(term).is_none() {
+                continue;
+            }
+            let code = soundex(term);
+            let boost = query.boost(term);
+            expanded.tokens.remove(term);
+            expanded.boosts.remove(term);
+            for token in self.phonetic_terms(&code) {
+                expanded.tokens.insert(token.clone(), *freq);
+                expanded.boosts.insert(token.clone(), boost);
+            }
         }
+        expanded
     }
-}
 
-// query_id Q0 doc_id rank score tag
-#[derive(Debug)]
-pub struct RankingResult {
-    query_id: u32,
-    doc_id: u32,
-    score: f32,
-    tag: u32,
-}
+    // Terms of the index with the soundex code, the codes are computed once per index
+    fn phonetic_terms(&self, code: &str) -> &[String] {
+        let phonetic_terms = self.index_stats.phonetic_terms.get_or_init(|| {
+            let mut phonetic_terms: HashMap<String, Vec<String>> = HashMap::new();
+            for term in self.inv_index.keys() {
+                phonetic_terms
+                    .entry(soundex(term))
+                    .or_default()
+                    .push(term.clone());
+            }
+            phonetic_terms
+        });
+        phonetic_terms.get(code).map_or(&[], Vec::as_slice)
+    }
 
-impl PartialOrd for RankingResult {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.score.partial_cmp(&other.score)
+    // Documents containing one of the excluded terms of the query
+    pub fn excluded_docs(&self, query: &TokenizedQuery) -> HashSet<u32> {
+        query
+            .excluded
+            .iter()
+            .filter_map(|term| self.inv_index.get(term))
+            .flat_map(|doc_map| doc_map.keys().copied())
+            .collect()
     }
-}
 
-impl PartialEq for RankingResult {
-    fn eq(&self, other: &Self) -> bool {
-        self.score.eq(&other.score)
+    fn is_candidate(&self, q_id: u32, doc_id: &u32) -> bool {
+        // zero length documents are scored as 0 and never make the results
+        if self
+            .doc_lengths
+            .get(doc_id)
+            .is_none_or(|length| *length == 0)
+        {
+            return false;
+        }
+        match self.candidate_pool {
+            Some(pool) => pool.get(&q_id).is_some_and(|docs| docs.contains(doc_id)),
+            None => true,
+        }
     }
-}
 
-impl Ord for RankingResult {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.score.partial_cmp(&other.score) {
-            Some(t) => t,
-            None => std::cmp::Ordering::Less,
+    // Applies the query rewrites enabled on the ranking (phonetic expansion)
+    fn prepare_query<'q>(&self, query: &'q TokenizedQuery) -> Cow<'q, TokenizedQuery> {
+        let mut query = match self.phonetic_index {
+            Some(_) => Cow::Owned(self.phonetic_expand(query)),
+            None => Cow::Borrowed(query),
+        };
+        if let Some(n) = self.keep_top_idf {
+            if query.tokens.len() > n {
+                query = Cow::Owned(self.keep_top_idf_terms(&query, n));
+            }
         }
+        query
     }
 
-    fn max(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        std::cmp::max_by(self, other, Ord::cmp)
+    // Copy of the query keeping only its n highest idf terms, ties are broken alphabetically
+    pub fn keep_top_idf_terms(&self, query: &TokenizedQuery, n: usize) -> TokenizedQuery {
+        let mut terms: Vec<(&String, f32)> = query
+            .tokens
+            .keys()
+            .map(|term| (term, self.idf(term)))
+            .collect();
+        terms.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let kept: HashSet<&String> = terms.into_iter().take(n).map(|(term, _)| term).collect();
+        let mut kept_query = query.clone();
+        kept_query.tokens.retain(|term, _| kept.contains(term));
+        kept_query
     }
 
-    fn min(self, other: Self) -> Self
-    where
-        Self: Sized,
-    {
-        std::cmp::min_by(self, other, Ord::cmp)
+    fn model_score(
+        &self,
+        doc_id: &u32,
+        query: &TokenizedQuery,
+        query_vector: &QueryVector,
+        model: ScoringModel,
+    ) -> f32 {
+        let score = match model {
+            ScoringModel::Cosine => self.cosine_with_query_vector(doc_id, query_vector),
+            ScoringModel::Bm25 => self.bm25_score(doc_id, query),
+            ScoringModel::LmJelinekMercer { lambda } => {
+                self.lm_jelinek_mercer_score(doc_id, query, lambda)
+            }
+            ScoringModel::LmDirichlet { mu } => self.lm_dirichlet_score(doc_id, query, mu),
+        };
+        self.adjust_score(doc_id, query, score)
     }
-}
 
-impl Eq for RankingResult {}
+    // Proximity bonus, bigram score and coord factor applied to the score of the model
+    fn adjust_score(&self, doc_id: &u32, query: &TokenizedQuery, mut score: f32) -> f32 {
+        score += self.proximity_bonus(doc_id, query);
+        if self.bigram_weight != 0.0 {
+            score += self.bigram_weight * self.bigram_score(doc_id, query);
+        }
+        if self.coord {
+            score *= self.coord_factor(doc_id, query);
+        }
+        score
+    }
+
+    // Number of results kept for the query
+    pub fn top_k_for(&self, query_id: u32) -> usize {
+        self.top_k_overrides
+            .and_then(|overrides| overrides.get(&query_id))
+            .map_or(self.top_k, |top_k| *top_k)
+    }
+
+    // Fraction of the distinct query terms found in the document
+    pub fn coord_factor(&self, doc_id: &u32, query: &TokenizedQuery) -> f32 {
+        if query.tokens.is_empty() {
+            return 0.0;
+        }
+        let matched = query
+            .tokens
+            .keys()
+            .filter(|term| self.tf(doc_id, term) > 0)
+            .count();
+        matched as f32 / query.tokens.len() as f32
+    }
+
+    // Score of one document for the query, the same rank_documents gives it with that model
+    pub fn score(&self, doc_id: &u32, query: &TokenizedQuery, model: ScoringModel) -> f32 {
+        let query = self.prepare_query(query);
+        let query_vector = self.query_vector(&query);
+        self.model_score(doc_id, &query, &query_vector, model)
+    }
+
+    pub fn rank_query(&self, query: &TokenizedQuery) -> BTreeSet<RankingResult> {
+        let Some(cache) = &self.query_cache else {
+            return self.rank_query_uncached(query);
+        };
+        let key = self.query_cache_key(query);
+        let q_id = query._id;
+        if let Some(results) = cache.lock().unwrap().get(key) {
+            // the same tokens may come from a query with another id
+            return results
+                .iter()
+                .map(|result| RankingResult {
+                    query_id: q_id,
+                    ..result.clone()
+                })
+                .collect();
+        }
+        let results = self.rank_query_uncached(query);
+        cache.lock().unwrap().insert(key, results.clone());
+        results
+    }
+
+    fn rank_query_uncached(&self, query: &TokenizedQuery) -> BTreeSet<RankingResult> {
+        self.rank_query_with_scorer(query, &self.model)
+    }
+
+    // Hash of everything in the query that changes its results
+    fn query_cache_key(&self, query: &TokenizedQuery) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut tokens: Vec<(&String, &TermFreq)> = query.tokens.iter().collect();
+        tokens.sort();
+        tokens.hash(&mut hasher);
+        let mut boosts: Vec<(&String, u32)> = query
+            .boosts
+            .iter()
+            .map(|(term, boost)| (term, boost.to_bits()))
+            .collect();
+        boosts.sort();
+        boosts.hash(&mut hasher);
+        let mut bigrams: Vec<(&String, &TermFreq)> = query.bigrams.iter().collect();
+        bigrams.sort();
+        bigrams.hash(&mut hasher);
+        let mut excluded: Vec<&String> = query.excluded.iter().collect();
+        excluded.sort();
+        excluded.hash(&mut hasher);
+        // the candidate pool and top_k overrides depend on the query id
+        if self.candidate_pool.is_some() || self.top_k_overrides.is_some() {
+            query._id.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    // Same candidates as rank_query, scored by the scorer
+    pub fn rank_query_with_scorer(
+        &self,
+        query: &TokenizedQuery,
+        scorer: &dyn Scorer,
+    ) -> BTreeSet<RankingResult> {
+        let top_k = self.top_k_for(query._id);
+        self.rank_query_by(query, top_k, |doc_id, query, query_vector| {
+            let ctx = ScoringContext {
+                ranking: self,
+                query_vector,
+            };
+            scorer.score(&ctx, *doc_id, query)
+        })
+    }
+
+    pub fn rank_documents_with_scorer(
+        &self,
+        queries: &[TokenizedQuery],
+        scorer: &dyn Scorer,
+    ) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        queries
+            .iter()
+            .map(|query| {
+                let query_id = query._id;
+                (query_id, self.rank_query_with_scorer(query, scorer))
+            })
+            .collect()
+    }
+
+    fn rank_query_by(
+        &self,
+        query: &TokenizedQuery,
+        top_k: usize,
+        score_fn: impl Fn(&u32, &TokenizedQuery, &QueryVector) -> f32,
+    ) -> BTreeSet<RankingResult> {
+        let q_id = query._id;
+        let mut results = TopK::new(top_k);
+        let query = self.prepare_query(query);
+        let query = query.as_ref();
+        let query_vector = self.query_vector(query);
+
+        let excluded = self.excluded_docs(query);
+        // sorted so documents are scored in the same order on every run, and each only once
+        let candidates: BTreeSet<u32> = query
+            .tokens
+            .keys()
+            .filter_map(|term| self.inv_index.get(term))
+            .flat_map(|doc_map| doc_map.keys().copied())
+            .filter(|doc_id| !excluded.contains(doc_id) && self.is_candidate(q_id, doc_id))
+            .collect();
+
+        for doc_id in &candidates {
+            let score = score_fn(doc_id, query, &query_vector);
+            // a NaN score has no place in the ordering of the set
+            if score.is_nan() {
+                continue;
+            }
+            results.push(RankingResult {
+                query_id: q_id,
+                doc_id: *doc_id,
+                score,
+            });
+        }
+        let results = results.into_set();
+        #[cfg(feature = "trace")]
+        self.trace_query(query, &results);
+        results
+    }
+
+    // Logs the query terms with their idf and the best documents, at the debug level
+    #[cfg(feature = "trace")]
+    fn trace_query(&self, query: &TokenizedQuery, results: &BTreeSet<RankingResult>) {
+        let mut terms: Vec<&String> = query.tokens.keys().collect();
+        terms.sort();
+        for term in terms {
+            match self.inv_index.contains_key(term) {
+                true => log::debug!(
+                    "query {}: term {:?} idf {:.4} df {}",
+                    query._id,
+                    term,
+                    self.idf(term),
+                    self.df(term)
+                ),
+                false => log::debug!("query {}: term {:?} not in the index", query._id, term),
+            }
+        }
+        for (rank, result) in results.iter().rev().take(TRACE_TOP_DOCS).enumerate() {
+            log::debug!(
+                "query {}: rank {} doc {} score {:.4}",
+                query._id,
+                rank + 1,
+                result.doc_id,
+                result.score
+            );
+        }
+    }
+
+    // Second stage of a two stage retrieval, re-scores the candidates of a first ranking with the
+    // scorer (e.g. |doc_id, query| ranking.score(doc_id, query, ScoringModel::Cosine)), keeping top_k
+    pub fn rerank(
+        &self,
+        candidates: &BTreeSet<RankingResult>,
+        query: &TokenizedQuery,
+        scorer: impl Fn(&u32, &TokenizedQuery) -> f32,
+    ) -> BTreeSet<RankingResult> {
+        let top_k = candidates
+            .first()
+            .map_or(self.top_k, |candidate| self.top_k_for(candidate.query_id));
+        let mut results = TopK::new(top_k);
+        for candidate in candidates {
+            let score = scorer(&candidate.doc_id, query);
+            if score.is_nan() {
+                continue;
+            }
+            results.push(RankingResult {
+                score,
+                ..candidate.clone()
+            });
+        }
+        results.into_set()
+    }
+
+    // Fuzzy ranking on the character n-grams shared by the query tokens and the documents, each
+    // n-gram scored like a bm25 term of the n-gram index built with the same n
+    pub fn rank_char_ngrams(
+        &self,
+        ngram_index: &InvertedIndex,
+        query: &TokenizedQuery,
+        n: usize,
+    ) -> BTreeSet<RankingResult> {
+        let q_id = query._id;
+        let query_ngrams = count_char_ngrams(&query.tokens, n);
+        let excluded = self.excluded_docs(query);
+        let mut candidates: BTreeSet<u32> = BTreeSet::new();
+        for gram in query_ngrams.keys() {
+            if let Some(doc_map) = ngram_index.get(gram) {
+                candidates.extend(doc_map.keys().filter(|doc_id| {
+                    !excluded.contains(doc_id) && self.is_candidate(q_id, doc_id)
+                }));
+            }
+        }
+
+        let mut results = TopK::new(self.top_k_for(q_id));
+        for doc_id in candidates {
+            let score: f64 = query_ngrams
+                .iter()
+                .map(|(gram, freq)| {
+                    let df = ngram_index.get(gram).map_or(0, |doc_map| doc_map.len());
+                    self.bm25_weight_in(ngram_index, df, &doc_id, gram) as f64 * *freq as f64
+                })
+                .sum();
+            if score.is_nan() {
+                continue;
+            }
+            results.push(RankingResult {
+                query_id: q_id,
+                doc_id,
+                score: score as f32,
+            });
+        }
+        results.into_set()
+    }
+
+    // Rocchio reweighted query: alpha * query + beta * centroid of the relevant documents
+    // - gamma * centroid of the non relevant ones, with bm25 weights for the documents.
+    // The non relevant documents are those the original query ranks that aren't in relevant_docs.
+    // Terms ending with a weight <= 0 are dropped, the weights are carried by the boosts.
+    pub fn rocchio_query(
+        &self,
+        query: &TokenizedQuery,
+        relevant_docs: &[u32],
+        alpha: f32,
+        beta: f32,
+        gamma: f32,
+    ) -> TokenizedQuery {
+        let relevant: HashSet<u32> = relevant_docs.iter().copied().collect();
+        let mut non_relevant: HashSet<u32> = HashSet::new();
+        if gamma != 0.0 {
+            non_relevant = self
+                .rank_query(query)
+                .iter()
+                .map(|result| result.doc_id)
+                .filter(|doc_id| !relevant.contains(doc_id))
+                .collect();
+        }
+
+        // {token: weight}
+        let mut weights: HashMap<String, f32> = query
+            .tokens
+            .iter()
+            .map(|(term, freq)| (term.clone(), alpha * (*freq as f32) * query.boost(term)))
+            .collect();
+        for (term, doc_map) in self.inv_index.iter() {
+            let mut centroid_weight = 0.0;
+            for (docs, factor) in [(&relevant, beta), (&non_relevant, -gamma)] {
+                if docs.is_empty() || factor == 0.0 {
+                    continue;
+                }
+                let sum: f32 = docs
+                    .iter()
+                    .filter(|doc_id| doc_map.contains_key(doc_id))
+                    .map(|doc_id| self.bm25_weight(doc_id, term))
+                    .sum();
+                centroid_weight += factor * sum / docs.len() as f32;
+            }
+            if centroid_weight != 0.0 {
+                *weights.entry(term.clone()).or_insert(0.0) += centroid_weight;
+            }
+        }
+        weights.retain(|_, weight| *weight > 0.0);
+
+        TokenizedQuery {
+            _id: query._id,
+            tokens: weights.keys().map(|term| (term.clone(), 1)).collect(),
+            metadata: query.metadata.clone(),
+            boosts: weights,
+            bigrams: query.bigrams.clone(),
+            excluded: query.excluded.clone(),
+            fingerprint: query.fingerprint,
+        }
+    }
+
+    // Ranks the documents for the query reweighted with the judged relevant documents
+    pub fn rank_with_feedback(
+        &self,
+        query: &TokenizedQuery,
+        relevant_docs: &[u32],
+        alpha: f32,
+        beta: f32,
+        gamma: f32,
+    ) -> BTreeSet<RankingResult> {
+        self.rank_query(&self.rocchio_query(query, relevant_docs, alpha, beta, gamma))
+    }
+
+    // Term at a time alternative to rank_documents. The scores are summed in another order so
+    // they may differ in the last bits, the language models fall back to document at a time
+    pub fn rank_documents_taat(
+        &self,
+        queries: &[TokenizedQuery],
+    ) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        queries
+            .iter()
+            .map(|query| (query._id, self.rank_query_taat(query)))
+            .collect()
+    }
+
+    // Reads the posting list of each query term once, adding the term's part of the score to an
+    // accumulator per document. The language models score the terms missing from a document
+    // too, so they aren't a sum over the postings and are ranked document at a time.
+    pub fn rank_query_taat(&self, query: &TokenizedQuery) -> BTreeSet<RankingResult> {
+        if !matches!(self.model, ScoringModel::Cosine | ScoringModel::Bm25) {
+            return self.rank_query_uncached(query);
+        }
+        let q_id = query._id;
+        let mut results = TopK::new(self.top_k_for(q_id));
+        let query = self.prepare_query(query);
+        let query = query.as_ref();
+        let excluded = self.excluded_docs(query);
+        let is_candidate =
+            |doc_id: &u32| !excluded.contains(doc_id) && self.is_candidate(q_id, doc_id);
+
+        // {doc_id: (score sum, sum of the squared document weights)}, the terms are added in the
+        // same order as the document at a time sums so the scores are identical
+        let mut accumulators: BTreeMap<u32, (f64, f64)> = BTreeMap::new();
+        let query_vector = self.query_vector(query);
+        match self.model {
+            ScoringModel::Cosine => {
+                for (term, query_term_weight) in &query_vector.weights {
+                    let Some(doc_map) = self.inv_index.get(*term) else {
+                        continue;
+                    };
+                    for doc_id in doc_map.keys().filter(|doc_id| is_candidate(doc_id)) {
+                        let doc_term_weight = self.cosine_doc_weight(doc_id, term) as f64;
+                        let accumulator = accumulators.entry(*doc_id).or_default();
+                        accumulator.0 += *query_term_weight as f64 * doc_term_weight;
+                        accumulator.1 += doc_term_weight * doc_term_weight;
+                    }
+                }
+            }
+            _ => {
+                for (term, freq) in &query.tokens {
+                    let Some(doc_map) = self.inv_index.get(term) else {
+                        continue;
+                    };
+                    let weight = *freq as f64 * query.boost(term) as f64;
+                    for doc_id in doc_map.keys().filter(|doc_id| is_candidate(doc_id)) {
+                        accumulators.entry(*doc_id).or_default().0 +=
+                            self.bm25_weight(doc_id, term) as f64 * weight;
+                    }
+                }
+            }
+        }
+
+        let q_len = query_vector.length as f64;
+        for (doc_id, (sum, doc_squares)) in accumulators {
+            let score = match self.model {
+                ScoringModel::Cosine => {
+                    let doc_len = doc_squares.sqrt();
+                    match doc_len > 0.0 && q_len > 0.0 {
+                        true => (sum / (doc_len * q_len)) as f32,
+                        false => 0.0,
+                    }
+                }
+                _ => sum as f32,
+            };
+            let score = self.adjust_score(&doc_id, query, score);
+            if score.is_nan() {
+                continue;
+            }
+            results.push(RankingResult {
+                query_id: q_id,
+                doc_id,
+                score,
+            });
+        }
+        let results = results.into_set();
+        #[cfg(feature = "trace")]
+        self.trace_query(query, &results);
+        results
+    }
+
+    // Preprocesses the raw text with config and returns its top_k (doc_id, score), best first.
+    // config must be the one the documents were indexed with, it is checked against
+    // index_fingerprint when set. The query gets the id 0, for the candidate pool if one is set.
+    pub fn search(
+        &self,
+        text: &str,
+        stopwords: &HashSet<String>,
+        config: &PreprocessConfig,
+        top_k: usize,
+    ) -> Result<Vec<(u32, f32)>, FingerprintMismatch> {
+        let query = process_query_text(text, stopwords, config);
+        if let (Some(index), Some(query)) = (&self.index_fingerprint, &query.fingerprint) {
+            index.verify_compatible(query)?;
+        }
+        Ok(self
+            .rank_query_by(&query, top_k, |doc_id, query, query_vector| {
+                self.model_score(doc_id, query, query_vector, self.model)
+            })
+            .iter()
+            .rev()
+            .map(|result| (result.doc_id, result.score))
+            .collect())
+    }
+
+    pub fn rank_documents(
+        &self,
+        queries: &[TokenizedQuery],
+    ) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        self.rank_documents_with_progress(queries, |_, _| {})
+    }
+
+    // progress is called with (processed, total) after each query
+    pub fn rank_documents_with_progress(
+        &self,
+        queries: &[TokenizedQuery],
+        mut progress: impl FnMut(usize, usize),
+    ) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        let mut results: BTreeMap<u32, BTreeSet<RankingResult>> = BTreeMap::new();
+
+        for (n, query) in queries.iter().enumerate() {
+            // queries without any result are kept so they show up in the coverage report
+            let q_results = self.rank_query(query);
+            results.insert(query._id, q_results);
+            progress(n + 1, queries.len());
+        }
+
+        results
+    }
+
+    // Writes each query's results as soon as it is ranked instead of keeping them all in memory.
+    // The file is the same save_results_to_file_with_order writes for rank_documents' results.
+    pub fn rank_and_write(
+        &self,
+        queries: &[TokenizedQuery],
+        run_name: &str,
+        file_path: &str,
+        output_top_k: Option<usize>,
+        precision: usize,
+        order: SortOrder,
+    ) -> Result<(), std::io::Error> {
+        let file = File::create(file_path)?;
+        let mut writer = BufWriter::new(file);
+        // rank_documents orders the queries by id and keeps the last of the queries sharing an id
+        let mut by_id: Vec<(u32, usize)> = queries
+            .iter()
+            .enumerate()
+            .map(|(n, query)| (query._id, n))
+            .collect();
+        by_id.sort();
+        for (i, (query_id, n)) in by_id.iter().enumerate() {
+            if by_id
+                .get(i + 1)
+                .is_some_and(|(next_id, _)| next_id == query_id)
+            {
+                continue;
+            }
+            write_query_results(
+                &mut writer,
+                &self.rank_query(&queries[*n]),
+                run_name,
+                output_top_k,
+                precision,
+                order,
+            )?;
+        }
+        writer.flush()
+    }
+
+    // Ranks the queries lazily, yielding each query's results from highest to lowest score
+    pub fn rank_documents_iter<'q>(
+        &'q self,
+        queries: &'q [TokenizedQuery],
+    ) -> impl Iterator<Item = Vec<RankingResult>> + 'q {
+        queries
+            .iter()
+            .map(|query| self.rank_query(query).into_iter().rev().collect())
+    }
+}
+
+// Smallest window covering at least one position of every list, each list sorted ascending
+fn min_window(term_positions: &[&Vec<u32>]) -> Option<u32> {
+    let mut positions: Vec<(u32, usize)> = term_positions
+        .iter()
+        .enumerate()
+        .flat_map(|(term, list)| list.iter().map(move |pos| (*pos, term)))
+        .collect();
+    positions.sort_unstable();
+
+    let mut counts = vec![0; term_positions.len()];
+    let mut covered = 0;
+    let mut start = 0;
+    let mut best: Option<u32> = None;
+    for end in 0..positions.len() {
+        let term = positions[end].1;
+        counts[term] += 1;
+        if counts[term] == 1 {
+            covered += 1;
+        }
+        // shrink the window from the left while it still covers every term
+        while covered == term_positions.len() {
+            let span = positions[end].0 - positions[start].0 + 1;
+            best = Some(best.map_or(span, |b| b.min(span)));
+            let first = positions[start].1;
+            counts[first] -= 1;
+            if counts[first] == 0 {
+                covered -= 1;
+            }
+            start += 1;
+        }
+    }
+    best
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterError {
+    // k1 must be >= 0
+    InvalidK1(f32),
+    // b must be in [0, 1]
+    InvalidB(f32),
+}
+
+impl std::fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterError::InvalidK1(k1) => write!(f, "k1 must be >= 0, got {}", k1),
+            ParameterError::InvalidB(b) => write!(f, "b must be between 0 and 1, got {}", b),
+        }
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
+pub fn validate_parameters(k1: f32, b: f32) -> Result<(), ParameterError> {
+    if k1.is_nan() || k1 < 0.0 {
+        return Err(ParameterError::InvalidK1(k1));
+    }
+    if !(0.0..=1.0).contains(&b) {
+        return Err(ParameterError::InvalidB(b));
+    }
+    Ok(())
+}
+
+// Chained configuration of a Ranking, build rejects invalid parameters like Ranking::try_init
+#[derive(Debug, Clone)]
+pub struct RankingBuilder {
+    k1: f32,
+    b: f32,
+    idf_variant: IdfVariant,
+    idf_smoothing: f32,
+    idf_offset: f32,
+    model: ScoringModel,
+    query_tf_scheme: QueryTfScheme,
+    top_k: usize,
+    coord: bool,
+    proximity_weight: f32,
+    bigram_weight: f32,
+    keep_top_idf: Option<usize>,
+}
+
+impl Default for RankingBuilder {
+    fn default() -> Self {
+        RankingBuilder {
+            k1: 1.2,
+            b: 0.75,
+            idf_variant: IdfVariant::Bm25Plus1,
+            idf_smoothing: DEFAULT_IDF_SMOOTHING,
+            idf_offset: DEFAULT_IDF_OFFSET,
+            model: ScoringModel::Cosine,
+            query_tf_scheme: QueryTfScheme::default(),
+            top_k: DEFAULT_TOP_K,
+            coord: false,
+            proximity_weight: 0.0,
+            bigram_weight: 0.0,
+            keep_top_idf: None,
+        }
+    }
+}
+
+impl RankingBuilder {
+    pub fn new() -> RankingBuilder {
+        RankingBuilder::default()
+    }
+
+    pub fn k1(mut self, k1: f32) -> Self {
+        self.k1 = k1;
+        self
+    }
+
+    pub fn b(mut self, b: f32) -> Self {
+        self.b = b;
+        self
+    }
+
+    pub fn idf_variant(mut self, idf_variant: IdfVariant) -> Self {
+        self.idf_variant = idf_variant;
+        self
+    }
+
+    pub fn idf_smoothing(mut self, idf_smoothing: f32) -> Self {
+        self.idf_smoothing = idf_smoothing;
+        self
+    }
+
+    pub fn idf_offset(mut self, idf_offset: f32) -> Self {
+        self.idf_offset = idf_offset;
+        self
+    }
+
+    pub fn model(mut self, model: ScoringModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    pub fn query_tf_scheme(mut self, query_tf_scheme: QueryTfScheme) -> Self {
+        self.query_tf_scheme = query_tf_scheme;
+        self
+    }
+
+    pub fn top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn coord(mut self, coord: bool) -> Self {
+        self.coord = coord;
+        self
+    }
+
+    pub fn proximity_weight(mut self, proximity_weight: f32) -> Self {
+        self.proximity_weight = proximity_weight;
+        self
+    }
+
+    pub fn bigram_weight(mut self, bigram_weight: f32) -> Self {
+        self.bigram_weight = bigram_weight;
+        self
+    }
+
+    pub fn keep_top_idf(mut self, keep_top_idf: Option<usize>) -> Self {
+        self.keep_top_idf = keep_top_idf;
+        self
+    }
+
+    // The optional indexes (positional, phonetic, bigram, fields) are still set on the Ranking
+    pub fn build<'a>(
+        &self,
+        doc_lengths: &'a HashMap<u32, u32>,
+        inverted_index: &'a InvertedIndex,
+    ) -> Result<Ranking<'a>, ParameterError> {
+        let mut ranking = Ranking::try_init(doc_lengths, inverted_index, self.k1, self.b)?;
+        ranking.idf_variant = self.idf_variant;
+        ranking.idf_smoothing = self.idf_smoothing;
+        ranking.idf_offset = self.idf_offset;
+        ranking.model = self.model;
+        ranking.query_tf_scheme = self.query_tf_scheme;
+        ranking.top_k = self.top_k;
+        ranking.coord = self.coord;
+        ranking.proximity_weight = self.proximity_weight;
+        ranking.bigram_weight = self.bigram_weight;
+        ranking.keep_top_idf = self.keep_top_idf;
+        Ok(ranking)
+    }
+}
+
+// What a Scorer sees of the ranking while scoring the documents of one query
+pub struct ScoringContext<'c, 'a> {
+    ranking: &'c Ranking<'a>,
+    // Computed once per query
+    query_vector: &'c QueryVector<'c>,
+}
+
+impl ScoringContext<'_, '_> {
+    pub fn idf(&self, term: &str) -> f32 {
+        self.ranking.idf(term)
+    }
+
+    pub fn tf(&self, doc_id: u32, term: &str) -> TermFreq {
+        self.ranking.tf(&doc_id, term)
+    }
+
+    pub fn doc_length(&self, doc_id: u32) -> u32 {
+        self.ranking.doc_length(&doc_id)
+    }
+
+    pub fn document_count(&self) -> u32 {
+        self.ranking.document_count()
+    }
+
+    // The rounded down avgdl bm25 uses
+    pub fn avgdl(&self) -> u32 {
+        self.ranking.avgdl
+    }
+
+    pub fn collection_freq(&self, term: &str) -> u64 {
+        self.ranking.collection_freq(term)
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.ranking.total_tokens()
+    }
+
+    pub fn bm25_weight(&self, doc_id: u32, term: &str) -> f32 {
+        self.ranking.bm25_weight(&doc_id, term)
+    }
+}
+
+// A ranking function plugged into rank_query_with_scorer
+pub trait Scorer {
+    fn score(&self, ctx: &ScoringContext, doc_id: u32, query: &TokenizedQuery) -> f32;
+}
+
+// The model with the proximity, bigram and coord adjustments, what rank_documents uses
+impl Scorer for ScoringModel {
+    fn score(&self, ctx: &ScoringContext, doc_id: u32, query: &TokenizedQuery) -> f32 {
+        ctx.ranking
+            .model_score(&doc_id, query, ctx.query_vector, *self)
+    }
+}
+
+pub struct Bm25Scorer;
+
+impl Scorer for Bm25Scorer {
+    fn score(&self, ctx: &ScoringContext, doc_id: u32, query: &TokenizedQuery) -> f32 {
+        ctx.ranking.bm25_score(&doc_id, query)
+    }
+}
+
+pub struct CosineScorer;
+
+impl Scorer for CosineScorer {
+    fn score(&self, ctx: &ScoringContext, doc_id: u32, _: &TokenizedQuery) -> f32 {
+        ctx.ranking
+            .cosine_with_query_vector(&doc_id, ctx.query_vector)
+    }
+}
+
+// Least recently used cache of query results
+#[derive(Debug)]
+struct QueryCache {
+    capacity: usize,
+    // {key: (results, last use)}
+    entries: HashMap<u64, (BTreeSet<RankingResult>, u64)>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl QueryCache {
+    fn new(capacity: usize) -> QueryCache {
+        QueryCache {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<&BTreeSet<RankingResult>> {
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some((results, last_use)) => {
+                self.hits += 1;
+                *last_use = self.clock;
+                Some(results)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: u64, results: BTreeSet<RankingResult>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // the capacity is expected to be small, a scan is cheaper than keeping an order
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert(key, (results, self.clock));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Statistics derived from the inverted index
+#[derive(Debug)]
+struct IndexStats {
+    // {token: document frequency}
+    df: HashMap<String, usize>,
+    // {token: collection frequency}
+    cf: HashMap<String, u64>,
+    // Sum of the collection frequencies
+    total_tokens: u64,
+    // {doc_id: number of tokens}, the |d| of the language models whatever the length source
+    doc_tokens: HashMap<u32, u64>,
+    // {soundex code: terms with that code}, built by the first phonetic expansion
+    phonetic_terms: OnceLock<HashMap<String, Vec<String>>>,
+}
+
+impl IndexStats {
+    fn build(inverted_index: &InvertedIndex) -> IndexStats {
+        let df: HashMap<String, usize> = inverted_index
+            .iter()
+            .map(|(term, doc_map)| (term.clone(), doc_map.len()))
+            .collect();
+        let cf: HashMap<String, u64> = inverted_index
+            .iter()
+            .map(|(term, doc_map)| {
+                (
+                    term.clone(),
+                    doc_map.values().map(|freq| *freq as u64).sum(),
+                )
+            })
+            .collect();
+        let total_tokens = cf.values().sum();
+        let mut doc_tokens: HashMap<u32, u64> = HashMap::new();
+        for doc_map in inverted_index.values() {
+            for (doc_id, freq) in doc_map {
+                *doc_tokens.entry(*doc_id).or_insert(0) += *freq as u64;
+            }
+        }
+        IndexStats {
+            df,
+            cf,
+            total_tokens,
+            doc_tokens,
+            phonetic_terms: OnceLock::new(),
+        }
+    }
+}
+
+// {field: average length}
+fn average_field_lengths(field_lengths: &FieldLengths) -> BTreeMap<String, f32> {
+    field_lengths
+        .iter()
+        .map(|(field, lengths)| {
+            let avgdl = match lengths.len() {
+                0 => 0.0,
+                n => lengths.values().sum::<u32>() as f32 / n as f32,
+            };
+            (field.clone(), avgdl)
+        })
+        .collect()
+}
+
+// Owned form of Ranking for sharing between threads, clones only copy the Arcs and the small
+// config maps. Every field is restored on the Ranking given by ranking().
+#[derive(Debug, Clone)]
+pub struct OwnedRanking {
+    pub k1: f32,
+    pub b: f32,
+    pub idf_variant: IdfVariant,
+    pub idf_smoothing: f32,
+    pub idf_offset: f32,
+    pub model: ScoringModel,
+    pub query_tf_scheme: QueryTfScheme,
+    pub proximity_weight: f32,
+    pub positional_index: Option<Arc<PositionalIndex>>,
+    pub phonetic_index: Option<Arc<PhoneticIndex>>,
+    pub bigram_index: Option<Arc<InvertedIndex>>,
+    pub bigram_weight: f32,
+    pub candidate_pool: Option<Arc<HashMap<u32, HashSet<u32>>>>,
+    pub top_k: usize,
+    pub top_k_overrides: Option<Arc<HashMap<u32, usize>>>,
+    pub coord: bool,
+    pub keep_top_idf: Option<usize>,
+    pub field_weights: HashMap<String, f32>,
+    pub index_fingerprint: Option<PreprocessingFingerprint>,
+    // Computed once in new, the documents of an OwnedRanking don't change
+    avgdl: u32,
+    num_doc: u32,
+    inv_index: Arc<InvertedIndex>,
+    doc_lengths: Arc<HashMap<u32, u32>>,
+    index_stats: Arc<IndexStats>,
+    // Set with set_fields
+    fields: Option<(Arc<FieldIndexes>, Arc<FieldLengths>)>,
+    field_avgdl: BTreeMap<String, f32>,
+}
+
+impl OwnedRanking {
+    // Parameters are clamped like Ranking::init, the rest of the config starts at its default
+    pub fn new(
+        doc_lengths: Arc<HashMap<u32, u32>>,
+        inverted_index: Arc<InvertedIndex>,
+        k1: f32,
+        b: f32,
+    ) -> OwnedRanking {
+        let ranking = Ranking::init(&doc_lengths, &inverted_index, k1, b);
+        OwnedRanking {
+            k1: ranking.k1,
+            b: ranking.b,
+            idf_variant: ranking.idf_variant,
+            idf_smoothing: ranking.idf_smoothing,
+            idf_offset: ranking.idf_offset,
+            model: ranking.model,
+            query_tf_scheme: ranking.query_tf_scheme,
+            proximity_weight: ranking.proximity_weight,
+            positional_index: None,
+            phonetic_index: None,
+            bigram_index: None,
+            bigram_weight: ranking.bigram_weight,
+            candidate_pool: None,
+            top_k: ranking.top_k,
+            top_k_overrides: None,
+            coord: ranking.coord,
+            keep_top_idf: ranking.keep_top_idf,
+            field_weights: HashMap::new(),
+            index_fingerprint: None,
+            avgdl: ranking.avgdl,
+            num_doc: ranking.num_doc,
+            index_stats: Arc::clone(&ranking.index_stats),
+            inv_index: inverted_index,
+            doc_lengths,
+            fields: None,
+            field_avgdl: BTreeMap::new(),
+        }
+    }
+
+    // Same as Ranking::set_fields, the average field lengths are computed here once
+    pub fn set_fields(
+        &mut self,
+        field_indexes: Arc<FieldIndexes>,
+        field_lengths: Arc<FieldLengths>,
+    ) {
+        self.field_avgdl = average_field_lengths(&field_lengths);
+        self.fields = Some((field_indexes, field_lengths));
+    }
+
+    // Borrowed Ranking over the shared index, nothing is recomputed from the documents
+    pub fn ranking(&self) -> Ranking<'_> {
+        let mut ranking = Ranking::build_with_stats(
+            &self.doc_lengths,
+            &self.inv_index,
+            self.k1,
+            self.b,
+            Arc::clone(&self.index_stats),
+        );
+        ranking.avgdl = self.avgdl;
+        ranking.num_doc = self.num_doc;
+        ranking.idf_variant = self.idf_variant;
+        ranking.idf_smoothing = self.idf_smoothing;
+        ranking.idf_offset = self.idf_offset;
+        ranking.model = self.model;
+        ranking.query_tf_scheme = self.query_tf_scheme;
+        ranking.proximity_weight = self.proximity_weight;
+        ranking.positional_index = self.positional_index.as_deref();
+        ranking.phonetic_index = self.phonetic_index.as_deref();
+        ranking.bigram_index = self.bigram_index.as_deref();
+        ranking.bigram_weight = self.bigram_weight;
+        ranking.candidate_pool = self.candidate_pool.as_deref();
+        ranking.top_k = self.top_k;
+        ranking.top_k_overrides = self.top_k_overrides.as_deref();
+        ranking.coord = self.coord;
+        ranking.keep_top_idf = self.keep_top_idf;
+        ranking.field_weights = self.field_weights.clone();
+        ranking.index_fingerprint = self.index_fingerprint;
+        if let Some((field_indexes, field_lengths)) = &self.fields {
+            ranking.field_indexes = Some(field_indexes);
+            ranking.field_lengths = Some(field_lengths);
+            ranking.field_avgdl = self.field_avgdl.clone();
+        }
+        ranking
+    }
+
+    pub fn score(&self, doc_id: &u32, query: &TokenizedQuery) -> f32 {
+        self.ranking().score(doc_id, query, self.model)
+    }
+
+    pub fn rank_query(&self, query: &TokenizedQuery) -> BTreeSet<RankingResult> {
+        self.ranking().rank_query(query)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FusionMethod {
+    // Sum of 1 / (k + rank) over the runs, k is usually 60
+    ReciprocalRank { k: f32 },
+    // Sum of the scores over the runs, the runs should have comparable score scales
+    CombSum,
+}
+
+// Fuses the rankings of several runs into a single set of the top_k results per query
+pub fn fuse_results(
+    runs: &[BTreeMap<u32, BTreeSet<RankingResult>>],
+    method: FusionMethod,
+    top_k: usize,
+) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+    // {query_id: {doc_id: fused score}}
+    let mut fused_scores: BTreeMap<u32, HashMap<u32, f32>> = BTreeMap::new();
+    for run in runs {
+        for (query_id, ranking) in run {
+            let doc_scores = fused_scores.entry(*query_id).or_default();
+            for (n, result) in ranking.iter().rev().enumerate() {
+                let score = match method {
+                    FusionMethod::ReciprocalRank { k } => 1.0 / (k + (n + 1) as f32),
+                    FusionMethod::CombSum => result.score,
+                };
+                *doc_scores.entry(result.doc_id).or_insert(0.0) += score;
+            }
+        }
+    }
+
+    let mut results: BTreeMap<u32, BTreeSet<RankingResult>> = BTreeMap::new();
+    for (query_id, doc_scores) in fused_scores {
+        let mut q_results = TopK::new(top_k);
+        for (doc_id, score) in doc_scores {
+            q_results.push(RankingResult {
+                query_id,
+                doc_id,
+                score,
+            });
+        }
+        results.insert(query_id, q_results.into_set());
+    }
+    results
+}
+
+// Lowers each score by strength * |length - avgdl| / avgdl to penalize extreme document lengths.
+// A strength of 0 leaves the results unchanged, the ranking of each query is re-sorted.
+pub fn apply_length_prior(
+    results: BTreeMap<u32, BTreeSet<RankingResult>>,
+    doc_lengths: &HashMap<u32, u32>,
+    strength: f32,
+) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+    if strength == 0.0 || doc_lengths.is_empty() {
+        return results;
+    }
+    let avgdl = doc_lengths
+        .values()
+        .map(|length| *length as f32)
+        .sum::<f32>()
+        / doc_lengths.len() as f32;
+    if avgdl == 0.0 {
+        return results;
+    }
+    results
+        .into_iter()
+        .map(|(query_id, ranking)| {
+            let ranking = ranking
+                .into_iter()
+                .map(|mut result| {
+                    let length = *doc_lengths.get(&result.doc_id).unwrap_or(&0) as f32;
+                    result.score -= strength * (length - avgdl).abs() / avgdl;
+                    result
+                })
+                .collect();
+            (query_id, ranking)
+        })
+        .collect()
+}
+
+// One term\tidf line per vocabulary term, sorted by term
+pub fn save_idf_table(ranking: &Ranking, file_path: &str) -> Result<(), std::io::Error> {
+    let file = File::create(file_path)?;
+    let mut writer = BufWriter::new(file);
+    let mut terms: Vec<&String> = ranking.inv_index.keys().collect();
+    terms.sort();
+    for term in terms {
+        writeln!(writer, "{}\t{}", term, ranking.idf(term))?;
+    }
+    writer.flush()
+}
+
+// run_name fills the last column of each line to identify the run
+pub fn save_results_to_file(
+    results: BTreeMap<u32, BTreeSet<RankingResult>>,
+    run_name: &str,
+    file_path: &str,
+    output_top_k: Option<usize>,
+) -> Result<(), std::io::Error> {
+    save_results_to_file_with_precision(
+        results,
+        run_name,
+        file_path,
+        output_top_k,
+        DEFAULT_SCORE_PRECISION,
+    )
+}
+
+// Scores are written with a fixed number of decimal places, never in scientific notation.
+// output_top_k limits the lines written per query, whatever top_k was used when ranking.
+pub fn save_results_to_file_with_precision(
+    results: BTreeMap<u32, BTreeSet<RankingResult>>,
+    run_name: &str,
+    file_path: &str,
+    output_top_k: Option<usize>,
+    precision: usize,
+) -> Result<(), std::io::Error> {
+    save_results_to_file_with_order(
+        results,
+        run_name,
+        file_path,
+        output_top_k,
+        precision,
+        SortOrder::default(),
+    )
+}
+
+// Lines are grouped by query_id, order only changes the order of the lines of each query.
+// The rank column stays the rank by score whatever the order.
+pub fn save_results_to_file_with_order(
+    results: BTreeMap<u32, BTreeSet<RankingResult>>,
+    run_name: &str,
+    file_path: &str,
+    output_top_k: Option<usize>,
+    precision: usize,
+    order: SortOrder,
+) -> Result<(), std::io::Error> {
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    for ranking in results.values() {
+        write_query_results(
+            &mut writer,
+            ranking,
+            run_name,
+            output_top_k,
+            precision,
+            order,
+        )?;
+    }
+    writer.flush()
+}
+
+// Writes the lines of one query's results
+fn write_query_results(
+    file: &mut impl Write,
+    ranking: &BTreeSet<RankingResult>,
+    run_name: &str,
+    output_top_k: Option<usize>,
+    precision: usize,
+    order: SortOrder,
+) -> Result<(), std::io::Error> {
+    // (rank, result)
+    let mut lines: Vec<(usize, &RankingResult)> = ranking
+        .iter()
+        .rev()
+        .take(output_top_k.unwrap_or(usize::MAX))
+        .enumerate()
+        .map(|(n, query_ranking)| (n + 1, query_ranking))
+        .collect();
+    match order {
+        SortOrder::ScoreDesc => {}
+        SortOrder::ScoreAsc => lines.reverse(),
+        SortOrder::DocIdAsc => lines.sort_by_key(|(_, query_ranking)| query_ranking.doc_id),
+    }
+    for (rank, query_ranking) in lines {
+        file.write_fmt(format_args!(
+            "{}  {}  {}  {}  {:.*}  {}\n",
+            query_ranking.query_id,
+            "Q0",
+            query_ranking.doc_id,
+            rank,
+            precision,
+            query_ranking.score,
+            run_name
+        ))?;
+    }
+    Ok(())
+}
+
+// Results for trec_eval -J (strict): "query_id Q0 doc_id rank score run_tag" separated by single
+// spaces, by query_id then by the written score descending. Like trec_eval, equal written scores
+// are ordered by doc_id descending (as strings), so the ranks match the order it reads them in.
+pub fn save_results_trec_strict(
+    results: &BTreeMap<u32, BTreeSet<RankingResult>>,
+    run_tag: &str,
+    file_path: &str,
+) -> Result<(), std::io::Error> {
+    if run_tag.is_empty() || run_tag.chars().any(char::is_whitespace) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid run tag {:?}, it must be one non empty word",
+                run_tag
+            ),
+        ));
+    }
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    for (query_id, ranking) in results {
+        // (written score, doc_id)
+        let mut lines: Vec<(String, String)> = ranking
+            .iter()
+            .map(|result| {
+                let score = format!("{:.*}", DEFAULT_SCORE_PRECISION, result.score);
+                (score, result.doc_id.to_string())
+            })
+            .collect();
+        lines.sort_by(|a, b| {
+            let score = |line: &(String, String)| line.0.parse::<f64>().unwrap_or(f64::MIN);
+            score(b).total_cmp(&score(a)).then_with(|| b.1.cmp(&a.1))
+        });
+        for (n, (score, doc_id)) in lines.iter().enumerate() {
+            writeln!(
+                writer,
+                "{} Q0 {} {} {} {}",
+                query_id,
+                doc_id,
+                n + 1,
+                score,
+                run_tag
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+// Order of the lines of each query in the results file
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SortOrder {
+    #[default]
+    ScoreDesc,
+    ScoreAsc,
+    DocIdAsc,
+}
+
+// The k highest results pushed, in a min-heap whose top is the result the next one has to beat
+struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<RankingResult>>,
+}
+
+impl TopK {
+    fn new(k: usize) -> TopK {
+        TopK {
+            k,
+            heap: BinaryHeap::with_capacity(k.saturating_add(1).min(1024)),
+        }
+    }
+
+    fn push(&mut self, result: RankingResult) {
+        if self.heap.len() < self.k {
+            self.heap.push(Reverse(result));
+        } else if self
+            .heap
+            .peek()
+            .is_some_and(|Reverse(lowest)| result > *lowest)
+        {
+            self.heap.pop();
+            self.heap.push(Reverse(result));
+        }
+    }
+
+    fn into_set(self) -> BTreeSet<RankingResult> {
+        self.heap
+            .into_iter()
+            .map(|Reverse(result)| result)
+            .collect()
+    }
+}
+
+// query_id Q0 doc_id rank score run_name
+#[derive(Debug, Clone)]
+pub struct RankingResult {
+    pub query_id: u32,
+    pub doc_id: u32,
+    pub score: f32,
+}
+
+impl PartialOrd for RankingResult {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankingResult {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Ord for RankingResult {
+    // Ties on the score are broken by doc_id (lower ranks higher) so equal scores aren't deduplicated
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        debug_assert!(
+            !self.score.is_nan() && !other.score.is_nan(),
+            "NaN score in RankingResult"
+        );
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| other.doc_id.cmp(&self.doc_id))
+            .then_with(|| other.query_id.cmp(&self.query_id))
+    }
+
+    fn max(self, other: Self) -> Self
+    where
+        Self: Sized,
+    {
+        std::cmp::max_by(self, other, Ord::cmp)
+    }
+
+    fn min(self, other: Self) -> Self
+    where
+        Self: Sized,
+    {
+        std::cmp::min_by(self, other, Ord::cmp)
+    }
+}
+
+impl Eq for RankingResult {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (doc_lengths, inverted index) of documents given as (doc_id, [(token, frequency)])
+    fn build(docs: &[(u32, &[(&str, TermFreq)])]) -> (HashMap<u32, u32>, InvertedIndex) {
+        let mut index: InvertedIndex = HashMap::new();
+        let mut doc_lengths: HashMap<u32, u32> = HashMap::new();
+        for (doc_id, tokens) in docs {
+            for (token, freq) in tokens.iter() {
+                index
+                    .entry(token.to_string())
+                    .or_default()
+                    .insert(*doc_id, *freq);
+            }
+            doc_lengths.insert(*doc_id, tokens.len() as u32);
+        }
+        (doc_lengths, index)
+    }
+
+    fn corpus() -> (HashMap<u32, u32>, InvertedIndex) {
+        build(&[
+            (1, &[("cell", 3), ("protein", 1)]),
+            (2, &[("protein", 3), ("cell", 1)]),
+            (3, &[("gene", 2), ("tumor", 1)]),
+            (4, &[("blood", 1), ("gene", 1)]),
+        ])
+    }
+
+    fn query(id: u32, tokens: &[(&str, TermFreq)]) -> TokenizedQuery {
+        TokenizedQuery {
+            _id: id,
+            tokens: tokens.iter().map(|(t, f)| (t.to_string(), *f)).collect(),
+            metadata: HashMap::new(),
+            boosts: HashMap::new(),
+            bigrams: HashMap::new(),
+            excluded: HashSet::new(),
+            fingerprint: None,
+        }
+    }
+
+    // doc_ids from the best result to the worst
+    fn ranked_ids(results: &BTreeSet<RankingResult>) -> Vec<u32> {
+        results.iter().rev().map(|result| result.doc_id).collect()
+    }
+
+    #[test]
+    fn boost_changes_the_ranking() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let mut q = query(1, &[("cell", 1), ("protein", 1)]);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q))[..2], [1, 2]);
+        q.boosts.insert("protein".to_string(), 3.0);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q))[..2], [2, 1]);
+    }
+
+    #[test]
+    fn impact_top_k_matches_exhaustive() {
+        let terms = ["cell", "protein", "gene", "tumor", "blood", "heart"];
+        let docs: Vec<(u32, Vec<(&str, TermFreq)>)> = (0..40u32)
+            .map(|doc_id| {
+                let tokens = terms
+                    .iter()
+                    .enumerate()
+                    .filter(|(n, _)| !(doc_id as usize + n).is_multiple_of(3))
+                    .map(|(n, term)| (*term, (doc_id + n as u32) % 5 + 1))
+                    .collect();
+                (doc_id, tokens)
+            })
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let (doc_lengths, index) = build(&docs);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let impact_index = ranking.build_impact_index();
+        for postings in impact_index.values() {
+            assert!(postings.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        }
+
+        let q = query(1, &[("cell", 1), ("tumor", 2), ("heart", 1)]);
+        let mut exhaustive: Vec<f32> = doc_lengths
+            .keys()
+            .map(|doc_id| ranking.bm25_score(doc_id, &q))
+            .collect();
+        exhaustive.sort_by(|a, b| b.total_cmp(a));
+        exhaustive.truncate(5);
+        let impact: Vec<f32> = ranking
+            .top_k_impact(&impact_index, &q, 5)
+            .iter()
+            .map(|(_, score)| *score)
+            .collect();
+        assert_eq!(impact, exhaustive);
+    }
+
+    #[test]
+    fn df_matches_the_posting_lists() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        for term in ["cell", "protein", "gene", "blood"] {
+            assert_eq!(ranking.df(term), index[term].len());
+        }
+        assert_eq!(ranking.df("heart"), 0);
+
+        // df follows the index once it is replaced
+        let (_, replacement) = build(&[(1, &[("heart", 1)]), (2, &[("heart", 2)])]);
+        ranking.set_index(&replacement);
+        assert_eq!(ranking.df("heart"), 2);
+        assert_eq!(ranking.df("cell"), 0);
+    }
+
+    #[test]
+    fn iterator_yields_the_batch_results() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let queries = vec![
+            query(1, &[("cell", 1)]),
+            query(2, &[("gene", 1), ("blood", 1)]),
+            query(3, &[("protein", 2)]),
+        ];
+        let batch = ranking.rank_documents(&queries);
+        let streamed: Vec<Vec<RankingResult>> = ranking.rank_documents_iter(&queries).collect();
+        assert_eq!(streamed.len(), queries.len());
+        let concatenated: Vec<RankingResult> = streamed.into_iter().flatten().collect();
+        let expected: Vec<RankingResult> = batch
+            .values()
+            .flat_map(|results| results.iter().rev().cloned())
+            .collect();
+        assert_eq!(concatenated, expected);
+    }
+
+    #[test]
+    fn closer_terms_outrank_farther_ones() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("protein", 1)]),
+            (2, &[("cell", 1), ("protein", 1)]),
+            (3, &[("gene", 1)]),
+        ]);
+        let positional_index: PositionalIndex = HashMap::from([
+            (
+                "cell".to_string(),
+                HashMap::from([(1, vec![0]), (2, vec![0])]),
+            ),
+            (
+                "protein".to_string(),
+                HashMap::from([(1, vec![20]), (2, vec![1])]),
+            ),
+        ]);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.positional_index = Some(&positional_index);
+        let q = query(1, &[("cell", 1), ("protein", 1)]);
+        // without the bonus the documents tie and the lower doc_id ranks first
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [1, 2]);
+        ranking.proximity_weight = 1.0;
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [2, 1]);
+    }
+
+    // Path of a file in the temporary directory, unique to the test process
+    fn temp_path(name: &str) -> String {
+        let file_name = format!("assignment1_{}_{}", std::process::id(), name);
+        std::env::temp_dir()
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn results(scores: &[(u32, u32, f32)]) -> BTreeMap<u32, BTreeSet<RankingResult>> {
+        let mut results: BTreeMap<u32, BTreeSet<RankingResult>> = BTreeMap::new();
+        for (query_id, doc_id, score) in scores {
+            results.entry(*query_id).or_default().insert(RankingResult {
+                query_id: *query_id,
+                doc_id: *doc_id,
+                score: *score,
+            });
+        }
+        results
+    }
+
+    #[test]
+    fn results_file_ends_with_the_run_name() {
+        let path = temp_path("run_name.tsv");
+        let results = results(&[(1, 10, 2.0), (1, 11, 1.0), (2, 10, 0.5)]);
+        save_results_to_file(results, "my_run", &path, None).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<Vec<&str>> = written
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines
+            .iter()
+            .all(|line| line.len() == 6 && line[5] == "my_run"));
+        assert_eq!(lines[0][..4], ["1", "Q0", "10", "1"]);
+    }
+
+    const MODELS: [ScoringModel; 4] = [
+        ScoringModel::Cosine,
+        ScoringModel::Bm25,
+        ScoringModel::LmJelinekMercer { lambda: 0.5 },
+        ScoringModel::LmDirichlet { mu: 100.0 },
+    ];
+
+    #[test]
+    fn all_stopword_documents_never_score_nan() {
+        use crate::{
+            indexing::{index_documents, IndexConfig},
+            preprocessing::Document,
+        };
+        let stopwords: HashSet<String> =
+            ["the", "of", "and"].iter().map(|w| w.to_string()).collect();
+        let documents = [(1, "cancer cells"), (2, "the of and"), (3, "blood")]
+            .iter()
+            .map(|(doc_id, text)| Document {
+                _id: doc_id.to_string(),
+                title: String::new(),
+                text: text.to_string(),
+            })
+            .collect();
+        let (index, doc_lengths) =
+            index_documents(documents, &stopwords, &IndexConfig::default()).unwrap();
+        assert_eq!(doc_lengths[&2], 0);
+        let q = process_query_text("cancer and blood", &stopwords, &PreprocessConfig::default());
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        for model in MODELS {
+            assert!(ranking.score(&2, &q, model).is_finite());
+            ranking.model = model;
+            let results = ranking.rank_query(&q);
+            assert!(results.iter().all(|result| !result.score.is_nan()));
+            assert!(results.iter().all(|result| result.doc_id != 2));
+        }
+    }
+
+    #[test]
+    fn nan_scores_are_left_out() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("protein", 1), ("gene", 1)]);
+        let candidates = ranking.rank_query(&q);
+        assert_eq!(candidates.len(), 4);
+        let reranked = ranking.rerank(&candidates, &q, |doc_id, _| match doc_id {
+            2 => f32::NAN,
+            _ => *doc_id as f32,
+        });
+        assert_eq!(ranked_ids(&reranked), [4, 3, 1]);
+        let results: Vec<&RankingResult> = reranked.iter().collect();
+        assert!(results.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // a NaN parameter makes every bm25 weight NaN
+        ranking.k1 = f32::NAN;
+        ranking.model = ScoringModel::Bm25;
+        assert!(ranking.rank_query(&q).is_empty());
+    }
+
+    #[test]
+    fn idf_variants_for_known_df() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("blood", 1)]),
+            (2, &[("cell", 1)]),
+            (3, &[("cell", 1)]),
+            (4, &[("gene", 1)]),
+        ]);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        // N = 4, df("blood") = 1 and df("cell") = 3
+        let expected = [
+            (
+                IdfVariant::Bm25Probabilistic,
+                (3.5f32 / 1.5).ln(),
+                (1.5f32 / 3.5).ln(),
+            ),
+            (IdfVariant::Standard, 4f32.ln(), (4f32 / 3.0).ln()),
+            (
+                IdfVariant::Bm25Plus1,
+                (3.5f32 / 1.5 + 1.0).ln(),
+                (1.5f32 / 3.5 + 1.0).ln(),
+            ),
+        ];
+        for (variant, blood, cell) in expected {
+            ranking.idf_variant = variant;
+            assert!((ranking.idf("blood") - blood).abs() < 1e-6);
+            assert!((ranking.idf("cell") - cell).abs() < 1e-6);
+            assert_eq!(ranking.idf("heart"), 0.0);
+        }
+        // a term in most documents has a negative probabilistic idf
+        ranking.idf_variant = IdfVariant::Bm25Probabilistic;
+        assert!(ranking.idf("cell") < 0.0);
+    }
+
+    #[test]
+    fn progress_is_reported_once_per_query() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let queries = vec![query(1, &[("cell", 1)]), query(2, &[("gene", 1)])];
+        let mut calls: Vec<(usize, usize)> = vec![];
+        ranking.rank_documents_with_progress(&queries, |n, total| calls.push((n, total)));
+        assert_eq!(calls, vec![(1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn misspelled_names_match_phonetically() {
+        use crate::indexing::build_phonetic_index;
+        assert_eq!(soundex("Smith"), soundex("Smyth"));
+        let (doc_lengths, index) = build(&[(1, &[("smith", 1)]), (2, &[("jones", 1)])]);
+        let phonetic_index = build_phonetic_index(&index);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("smyth", 1)]);
+        assert!(ranking.rank_query(&q).is_empty());
+        ranking.phonetic_index = Some(&phonetic_index);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [1]);
+        assert_eq!(
+            ranked_ids(&ranking.rank_query(&query(2, &[("smith", 1)]))),
+            [1]
+        );
+        // a new index gets the codes of its own terms
+        let (_, other) = build(&[(2, &[("smithe", 1)])]);
+        let other_phonetic = build_phonetic_index(&other);
+        ranking.set_index(&other);
+        ranking.phonetic_index = Some(&other_phonetic);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [2]);
+    }
+
+    #[test]
+    fn vocabulary_df_matches_manual_counts() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let vocabulary: HashMap<&str, usize> = ranking.vocabulary_with_df().collect();
+        let expected = HashMap::from([
+            ("cell", 2),
+            ("protein", 2),
+            ("gene", 2),
+            ("tumor", 1),
+            ("blood", 1),
+        ]);
+        assert_eq!(vocabulary, expected);
+    }
+
+    #[test]
+    fn only_pooled_documents_are_returned() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("gene", 1)]);
+        assert_eq!(ranking.rank_query(&q).len(), 4);
+        let pool = HashMap::from([(1, HashSet::from([2, 3]))]);
+        ranking.candidate_pool = Some(&pool);
+        let mut doc_ids = ranked_ids(&ranking.rank_query(&q));
+        doc_ids.sort();
+        assert_eq!(doc_ids, [2, 3]);
+    }
+
+    #[test]
+    fn tiny_scores_are_written_without_exponent() {
+        let path = temp_path("precision.tsv");
+        let tiny = results(&[(1, 10, 1e-7)]);
+        save_results_to_file(tiny.clone(), "run", &path, None).unwrap();
+        let default = std::fs::read_to_string(&path).unwrap();
+        save_results_to_file_with_precision(tiny, "run", &path, None, 3).unwrap();
+        let three = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(default.split_whitespace().nth(4), Some("0.000000"));
+        assert_eq!(three.split_whitespace().nth(4), Some("0.000"));
+    }
+
+    #[test]
+    fn ranked_scores_equal_cosine_similarity() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 2), ("gene", 1), ("blood", 1)]);
+        let results = ranking.rank_query(&q);
+        assert_eq!(results.len(), 4);
+        for result in &results {
+            assert_eq!(result.score, ranking.cosine_similarity(&result.doc_id, &q));
+        }
+    }
+
+    #[test]
+    fn score_matches_the_ranked_score() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("protein", 1), ("tumor", 1)]);
+        for model in MODELS {
+            ranking.model = model;
+            for result in ranking.rank_query(&q) {
+                assert_eq!(result.score, ranking.score(&result.doc_id, &q, model));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected_or_clamped() {
+        let (doc_lengths, index) = corpus();
+        assert!(matches!(
+            Ranking::try_init(&doc_lengths, &index, -1.0, 0.75),
+            Err(ParameterError::InvalidK1(_))
+        ));
+        assert!(matches!(
+            Ranking::try_init(&doc_lengths, &index, 1.2, 1.5),
+            Err(ParameterError::InvalidB(_))
+        ));
+        let valid = Ranking::try_init(&doc_lengths, &index, 1.2, 0.75).unwrap();
+        assert_eq!((valid.k1, valid.b), (1.2, 0.75));
+        let clamped = Ranking::init(&doc_lengths, &index, -1.0, 1.5);
+        assert_eq!((clamped.k1, clamped.b), (0.0, 1.0));
+        let clamped = Ranking::init(&doc_lengths, &index, 1.2, f32::NAN);
+        assert_eq!((clamped.k1, clamped.b), (1.2, 0.0));
+    }
+
+    #[test]
+    fn reciprocal_rank_fusion_reorders_the_runs() {
+        let first = results(&[(1, 1, 3.0), (1, 2, 2.0), (1, 3, 1.0)]);
+        let second = results(&[(1, 2, 30.0), (1, 3, 20.0), (1, 1, 10.0)]);
+        let fused = fuse_results(
+            &[first.clone(), second.clone()],
+            FusionMethod::ReciprocalRank { k: 60.0 },
+            10,
+        );
+        // 1/61 + 1/62 for doc 2, 1/61 + 1/63 for doc 1 and 1/62 + 1/63 for doc 3
+        assert_eq!(ranked_ids(&fused[&1]), [2, 1, 3]);
+        let fused = fuse_results(&[first, second], FusionMethod::CombSum, 2);
+        assert_eq!(ranked_ids(&fused[&1]), [2, 3]);
+    }
+
+    #[test]
+    fn only_out_of_vocabulary_terms_are_reported() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("heart", 1)]);
+        assert_eq!(ranking.query_oov_terms(&q), ["heart"]);
+        let batch = ranking.batch_oov_terms(&[q, query(2, &[("gene", 1)])]);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[&1], ["heart"]);
+    }
+
+    #[test]
+    fn title_matches_use_the_title_average_length() {
+        use crate::{
+            indexing::{
+                build_field_indexes, build_field_lengths, build_inverted_index_parallel,
+                LengthSource,
+            },
+            preprocessing::{TokenizedDocument, TEXT_FIELD, TITLE_FIELD},
+        };
+        let tokens = |terms: &[&str]| terms.iter().map(|t| (t.to_string(), 1)).collect();
+        let documents = vec![
+            TokenizedDocument::new(
+                1,
+                tokens(&["cancer"]),
+                tokens(&["cell", "gene", "blood", "heart"]),
+            ),
+            TokenizedDocument::new(
+                2,
+                tokens(&["tumor"]),
+                tokens(&["cell", "protein", "liver", "lung"]),
+            ),
+            TokenizedDocument::new(3, tokens(&["brain"]), tokens(&["cell"])),
+        ];
+        let index = build_inverted_index_parallel(&documents);
+        let doc_lengths: HashMap<u32, u32> = documents
+            .iter()
+            .map(|doc| (doc._id, doc.tokens().len() as u32))
+            .collect();
+        let field_indexes = build_field_indexes(&documents);
+        let field_lengths = build_field_lengths(&documents, LengthSource::UniqueTerms);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.set_fields(&field_indexes, &field_lengths);
+        assert_eq!(ranking.avgdl, 4);
+        assert_eq!(ranking.field_avgdl(TITLE_FIELD), 1.0);
+        assert_eq!(ranking.field_avgdl(TEXT_FIELD), 3.0);
+
+        // the title has the average title length, its length doesn't count against it
+        let idf = ranking.idf("cancer");
+        let expected = idf / (ranking.k1 + 1.0);
+        let weight = ranking.field_bm25_weight(TITLE_FIELD, &1, "cancer");
+        assert!((weight - expected).abs() < 1e-6);
+        assert!((ranking.bm25_weight(&1, "cancer") - expected).abs() < 1e-6);
+        let combined = ranking.bm25_weight_in(&index, ranking.df("cancer"), &1, "cancer");
+        // normalized by the length of the whole document, longer than average, it counts for less
+        assert!(combined < weight - 1e-3);
+    }
+
+    #[test]
+    fn output_top_k_limits_the_lines_per_query() {
+        let docs: Vec<(u32, [(&str, TermFreq); 2])> = (0..60u32)
+            .map(|doc_id| (doc_id, [("cell", doc_id % 4 + 1), ("gene", doc_id % 3 + 1)]))
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let (doc_lengths, index) = build(&docs);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.top_k = 50;
+        let queries = [query(1, &[("cell", 1)]), query(2, &[("gene", 1)])];
+        let results = ranking.rank_documents(&queries);
+        assert!(results.values().all(|ranking| ranking.len() == 50));
+
+        let path = temp_path("output_top_k.tsv");
+        save_results_to_file(results, "run", &path, Some(10)).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for query_id in ["1", "2"] {
+            let lines = written
+                .lines()
+                .filter(|line| line.split_whitespace().next() == Some(query_id))
+                .count();
+            assert_eq!(lines, 10);
+        }
+    }
+
+    #[test]
+    fn jelinek_mercer_favors_the_rare_term() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("blood", 1)]),
+            (2, &[("cell", 1), ("heart", 1)]),
+            (3, &[("cell", 1), ("lung", 1)]),
+            (4, &[("cell", 1), ("liver", 1)]),
+            (5, &[("prion", 1), ("brain", 1)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("prion", 1)]);
+        let model = ScoringModel::LmJelinekMercer { lambda: 0.5 };
+        // both documents match one query term and have the same length
+        assert!(ranking.score(&5, &q, model) > ranking.score(&1, &q, model));
+    }
+
+    #[test]
+    fn dirichlet_smoothing_depends_on_the_length() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 2), ("blood", 1)]),
+            (
+                2,
+                &[
+                    ("cell", 2),
+                    ("heart", 1),
+                    ("lung", 1),
+                    ("liver", 1),
+                    ("brain", 1),
+                ],
+            ),
+            (3, &[("gene", 1), ("protein", 1)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1)]);
+        let gap = |mu: f32| {
+            let model = ScoringModel::LmDirichlet { mu };
+            ranking.score(&1, &q, model) - ranking.score(&2, &q, model)
+        };
+        // the same frequency is a larger share of the shorter document
+        assert!(gap(1.0) > 0.0);
+        // a larger prior pulls both documents towards the collection model
+        assert!(gap(1000.0) > 0.0);
+        assert!(gap(1000.0) < gap(1.0));
+    }
+
+    #[test]
+    fn language_models_count_every_token() {
+        // doc_lengths counts distinct terms, the language models need the 4 tokens of document 1
+        let (doc_lengths, index) = build(&[(1, &[("cell", 3), ("gene", 1)]), (2, &[("gene", 2)])]);
+        assert_eq!(doc_lengths[&1], 2);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1)]);
+        // P(cell|d) = 3 / 4 without smoothing
+        let model = ScoringModel::LmJelinekMercer { lambda: 0.0 };
+        assert!((ranking.score(&1, &q, model) - 0.75f32.ln()).abs() < 1e-6);
+        // (3 + mu * 3 / 6) / (4 + mu)
+        let model = ScoringModel::LmDirichlet { mu: 2.0 };
+        assert!((ranking.score(&1, &q, model) - (4.0f32 / 6.0).ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn stats_follow_added_documents() {
+        use crate::{
+            indexing::{add_document, LengthSource},
+            preprocessing::TokenizedDocument,
+        };
+        let (doc_lengths, index) = corpus();
+        let (mut new_lengths, mut new_index) = (doc_lengths.clone(), index.clone());
+        let text = [
+            "cell", "gene", "blood", "heart", "lung", "liver", "brain", "bone",
+        ]
+        .iter()
+        .map(|token| (token.to_string(), 1))
+        .collect();
+        let document = TokenizedDocument::new(5, HashMap::new(), text);
+        add_document(
+            &mut new_index,
+            &mut new_lengths,
+            &document,
+            LengthSource::UniqueTerms,
+        );
+
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        assert_eq!((ranking.num_doc, ranking.avgdl), (4, 2));
+        ranking.set_documents(&new_index, &new_lengths);
+        // (2 + 2 + 2 + 2 + 8) / 5
+        assert_eq!((ranking.num_doc, ranking.avgdl), (5, 3));
+        assert_eq!(ranking.df("cell"), 3);
+    }
+
+    #[test]
+    fn length_prior_spares_average_length_documents() {
+        // avgdl is 10
+        let doc_lengths = HashMap::from([(1, 10), (2, 1), (3, 19), (4, 10)]);
+        let scores = results(&[(1, 1, 1.0), (1, 2, 1.2), (1, 3, 1.1)]);
+        assert_eq!(
+            apply_length_prior(scores.clone(), &doc_lengths, 0.0),
+            scores
+        );
+        let adjusted = apply_length_prior(scores, &doc_lengths, 0.5);
+        let score = |doc_id: u32| {
+            adjusted[&1]
+                .iter()
+                .find(|result| result.doc_id == doc_id)
+                .unwrap()
+                .score
+        };
+        assert_eq!(score(1), 1.0);
+        assert!((score(2) - (1.2 - 0.5 * 0.9)).abs() < 1e-6);
+        assert!((score(3) - (1.1 - 0.5 * 0.9)).abs() < 1e-6);
+        assert_eq!(ranked_ids(&adjusted[&1]), [1, 2, 3]);
+    }
+
+    #[test]
+    fn owned_ranking_scores_from_several_threads() {
+        let (doc_lengths, index) = corpus();
+        let expected: Vec<f32> = {
+            let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+            let q = query(1, &[("cell", 1), ("gene", 1)]);
+            (1..=4)
+                .map(|doc_id| ranking.score(&doc_id, &q, ScoringModel::Bm25))
+                .collect()
+        };
+        let mut owned = OwnedRanking::new(Arc::new(doc_lengths), Arc::new(index), 1.2, 0.75);
+        owned.model = ScoringModel::Bm25;
+        let handles: Vec<std::thread::JoinHandle<Vec<f32>>> = (0..4)
+            .map(|_| {
+                let owned = owned.clone();
+                std::thread::spawn(move || {
+                    let q = query(1, &[("cell", 1), ("gene", 1)]);
+                    (1..=4).map(|doc_id| owned.score(&doc_id, &q)).collect()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn feedback_boosts_documents_sharing_a_relevant_term() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("prion", 2)]),
+            (2, &[("cell", 1), ("blood", 1)]),
+            (3, &[("prion", 1), ("brain", 1)]),
+            (4, &[("gene", 1), ("brain", 1)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1)]);
+        assert!(!ranked_ids(&ranking.rank_query(&q)).contains(&3));
+        // prion is only shared by the relevant document 1 and document 3
+        let feedback = ranked_ids(&ranking.rank_with_feedback(&q, &[1], 1.0, 0.75, 0.0));
+        assert!(feedback.contains(&3));
+        assert!(!feedback.contains(&4));
+    }
+
+    #[test]
+    fn collection_freq_sums_the_postings() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        for (term, postings) in &index {
+            let sum: u64 = postings.values().map(|freq| *freq as u64).sum();
+            assert_eq!(ranking.collection_freq(term), sum);
+        }
+        assert_eq!(ranking.collection_freq("cell"), 4);
+        assert_eq!(ranking.collection_freq("heart"), 0);
+        // 4 + 4 + 3 + 2
+        assert_eq!(ranking.total_tokens(), 13);
+    }
+
+    #[test]
+    fn lines_follow_the_sort_order() {
+        let scores = results(&[
+            (1, 30, 2.0),
+            (1, 10, 1.0),
+            (1, 20, 3.0),
+            (2, 40, 1.0),
+            (2, 5, 2.0),
+        ]);
+        let path = temp_path("sort_order.tsv");
+        let written = |order: SortOrder| {
+            save_results_to_file_with_order(scores.clone(), "run", &path, None, 3, order).unwrap();
+            let text = std::fs::read_to_string(&path).unwrap();
+            // (query_id, doc_id, rank)
+            text.lines()
+                .map(|line| {
+                    let columns: Vec<&str> = line.split_whitespace().collect();
+                    let column = |n: usize| columns[n].parse::<u32>().unwrap();
+                    (column(0), column(2), column(3))
+                })
+                .collect::<Vec<(u32, u32, u32)>>()
+        };
+        assert_eq!(
+            written(SortOrder::ScoreDesc),
+            [(1, 20, 1), (1, 30, 2), (1, 10, 3), (2, 5, 1), (2, 40, 2)]
+        );
+        assert_eq!(
+            written(SortOrder::ScoreAsc),
+            [(1, 10, 3), (1, 30, 2), (1, 20, 1), (2, 40, 2), (2, 5, 1)]
+        );
+        assert_eq!(
+            written(SortOrder::DocIdAsc),
+            [(1, 10, 3), (1, 20, 1), (1, 30, 2), (2, 5, 1), (2, 40, 2)]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn upper_bound_covers_every_posting() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 3), ("protein", 1)]),
+            (2, &[("cell", 1)]),
+            (3, &[("cell", 2), ("gene", 1), ("tumor", 1), ("blood", 1)]),
+            (4, &[("gene", 4)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        for (term, postings) in &index {
+            let bound = ranking.term_upper_bound(term);
+            for doc_id in postings.keys() {
+                assert!(bound >= ranking.bm25_weight(doc_id, term));
+            }
+        }
+        // document 4 has both the highest tf and the shortest length, the bound is its weight
+        assert_eq!(
+            ranking.term_upper_bound("gene"),
+            ranking.bm25_weight(&4, "gene")
+        );
+        assert_eq!(ranking.term_upper_bound("heart"), 0.0);
+    }
+
+    #[test]
+    fn only_the_highest_idf_terms_are_scored() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("prion", 1), ("cell", 2), ("gene", 1)]),
+            (2, &[("tumor", 1), ("cell", 1), ("gene", 2)]),
+            (3, &[("cell", 1), ("gene", 1)]),
+            (4, &[("cell", 1), ("blood", 1)]),
+        ]);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.model = ScoringModel::Bm25;
+        ranking.keep_top_idf = Some(2);
+        let q = query(1, &[("prion", 1), ("tumor", 1), ("cell", 1), ("gene", 1)]);
+        let results = ranking.rank_query(&q);
+        // cell and gene have the lowest idf, only prion and tumor count
+        assert_eq!(results.len(), 2);
+        for result in &results {
+            let expected = ranking.bm25_weight(&result.doc_id, "prion")
+                + ranking.bm25_weight(&result.doc_id, "tumor");
+            assert!((result.score - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn incremental_output_matches_the_two_step_output() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let queries = [
+            query(12, &[("gene", 1), ("blood", 1)]),
+            query(3, &[("cell", 2), ("protein", 1)]),
+            query(7, &[("tumor", 1)]),
+        ];
+        let two_step_path = temp_path("two_step.tsv");
+        save_results_to_file_with_order(
+            ranking.rank_documents(&queries),
+            "run",
+            &two_step_path,
+            Some(2),
+            3,
+            SortOrder::DocIdAsc,
+        )
+        .unwrap();
+        let incremental_path = temp_path("incremental.tsv");
+        ranking
+            .rank_and_write(
+                &queries,
+                "run",
+                &incremental_path,
+                Some(2),
+                3,
+                SortOrder::DocIdAsc,
+            )
+            .unwrap();
+        let two_step = std::fs::read_to_string(&two_step_path).unwrap();
+        let incremental = std::fs::read_to_string(&incremental_path).unwrap();
+        std::fs::remove_file(&two_step_path).unwrap();
+        std::fs::remove_file(&incremental_path).unwrap();
+        assert!(!incremental.is_empty());
+        assert_eq!(incremental, two_step);
+    }
+
+    #[test]
+    fn weights_of_an_index_built_from_triples() {
+        use crate::indexing::index_from_triples;
+        let index = index_from_triples([
+            ("cell".to_string(), 1, 2),
+            ("cell".to_string(), 2, 1),
+            ("gene".to_string(), 2, 3),
+            ("blood".to_string(), 3, 1),
+        ]);
+        let doc_lengths = HashMap::from([(1, 2), (2, 4), (3, 3)]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        assert_eq!((ranking.num_doc, ranking.avgdl), (3, 3));
+        // ln((N - df + 0.5) / (df + 0.5) + 1)
+        let idf_cell = (1.5f32 / 2.5 + 1.0).ln();
+        let idf_gene = (2.5f32 / 1.5 + 1.0).ln();
+        assert!((ranking.idf("cell") - idf_cell).abs() < 1e-6);
+        assert!((ranking.idf("gene") - idf_gene).abs() < 1e-6);
+        // idf * tf / (k1 * (1 - b + b * length / avgdl) + tf)
+        let expected = idf_cell * 2.0 / (1.2 * (0.25 + 0.75 * 2.0 / 3.0) + 2.0);
+        assert!((ranking.bm25_weight(&1, "cell") - expected).abs() < 1e-6);
+        let expected = idf_gene * 3.0 / (1.2 * (0.25 + 0.75 * 4.0 / 3.0) + 3.0);
+        assert!((ranking.bm25_weight(&2, "gene") - expected).abs() < 1e-6);
+        assert_eq!(ranking.bm25_weight(&1, "gene"), 0.0);
+    }
+
+    #[test]
+    fn rerank_reorders_the_candidates() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("protein", 1)]);
+        let candidates = ranking.rank_query(&q);
+        assert_eq!(ranked_ids(&candidates), [1, 2]);
+        // the second stage only scores protein, which document 2 has the most of
+        let reranked = ranking.rerank(&candidates, &q, |doc_id, _| {
+            ranking.bm25_weight(doc_id, "protein")
+        });
+        assert_eq!(ranked_ids(&reranked), [2, 1]);
+        // documents outside the candidates are never added
+        let reranked = ranking.rerank(&candidates, &q, |doc_id, _| *doc_id as f32);
+        assert_eq!(ranked_ids(&reranked), [2, 1]);
+    }
+
+    #[test]
+    fn collection_stats_match_the_index() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 3), ("protein", 1)]),
+            (2, &[("cell", 1)]),
+            (3, &[("gene", 2), ("tumor", 1), ("blood", 1)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        assert_eq!(ranking.vocabulary_size(), 5);
+        assert_eq!(ranking.document_count(), 3);
+        // (2 + 1 + 3) / 3
+        assert_eq!(ranking.average_doc_length(), 2.0);
+
+        let empty_lengths = HashMap::new();
+        let empty_index = HashMap::new();
+        let empty = Ranking::init(&empty_lengths, &empty_index, 1.2, 0.75);
+        assert_eq!((empty.vocabulary_size(), empty.document_count()), (0, 0));
+        assert_eq!(empty.average_doc_length(), 0.0);
+    }
+
+    #[test]
+    fn builder_options_take_effect() {
+        let (doc_lengths, index) = corpus();
+        let ranking = RankingBuilder::new()
+            .k1(2.0)
+            .b(0.5)
+            .idf_variant(IdfVariant::Standard)
+            .model(ScoringModel::Bm25)
+            .top_k(1)
+            .build(&doc_lengths, &index)
+            .unwrap();
+        assert_eq!((ranking.k1, ranking.b), (2.0, 0.5));
+        assert_eq!(ranking.idf_variant, IdfVariant::Standard);
+        assert_eq!(ranking.model, ScoringModel::Bm25);
+        // ln(N / df)
+        assert!((ranking.idf("cell") - 2f32.ln()).abs() < 1e-6);
+        let q = query(1, &[("cell", 1)]);
+        let results = ranking.rank_query(&q);
+        assert_eq!(ranked_ids(&results), [1]);
+        let best = results.last().unwrap();
+        assert_eq!(best.score, ranking.bm25_score(&1, &q));
+        assert!(matches!(
+            RankingBuilder::new().b(2.0).build(&doc_lengths, &index),
+            Err(ParameterError::InvalidB(_))
+        ));
+    }
+
+    #[test]
+    fn idf_table_has_every_term_sorted() {
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let path = temp_path("idf.tsv");
+        save_idf_table(&ranking, &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let rows: Vec<(&str, f32)> = written
+            .lines()
+            .map(|line| {
+                let (term, idf) = line.split_once('\t').unwrap();
+                (term, idf.parse::<f32>().unwrap())
+            })
+            .collect();
+        let terms: Vec<&str> = rows.iter().map(|(term, _)| *term).collect();
+        assert_eq!(terms, ["blood", "cell", "gene", "protein", "tumor"]);
+        for (term, idf) in rows {
+            assert_eq!(idf, ranking.idf(term));
+        }
+    }
+
+    #[test]
+    fn fielded_cosine_weights_the_title_match() {
+        use crate::{
+            indexing::{
+                build_field_indexes, build_field_lengths, build_inverted_index_parallel,
+                LengthSource,
+            },
+            preprocessing::{TokenizedDocument, TEXT_FIELD, TITLE_FIELD},
+        };
+        let tokens = |terms: &[&str]| terms.iter().map(|t| (t.to_string(), 1)).collect();
+        let documents = vec![
+            TokenizedDocument::new(1, tokens(&["cancer"]), tokens(&["cell", "gene", "blood"])),
+            TokenizedDocument::new(2, tokens(&["tumor"]), tokens(&["cell", "protein"])),
+            TokenizedDocument::new(3, tokens(&["brain"]), tokens(&["gene"])),
+        ];
+        let index = build_inverted_index_parallel(&documents);
+        let doc_lengths: HashMap<u32, u32> = documents
+            .iter()
+            .map(|doc| (doc._id, doc.tokens().len() as u32))
+            .collect();
+        let field_indexes = build_field_indexes(&documents);
+        let field_lengths = build_field_lengths(&documents, LengthSource::UniqueTerms);
+        let q = query(1, &[("cancer", 1), ("cell", 1)]);
+
+        let merged = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let merged_score = merged.cosine_similarity(&1, &q);
+        let mut fielded = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        fielded.set_fields(&field_indexes, &field_lengths);
+        fielded.field_weights = HashMap::from([
+            (TITLE_FIELD.to_string(), 2.0),
+            (TEXT_FIELD.to_string(), 1.0),
+        ]);
+        let fielded_score = fielded.cosine_similarity(&1, &q);
+
+        // cancer only matches the title, weighted twice and normalized by the title length
+        let doc = [
+            2.0 * fielded.field_bm25_weight(TITLE_FIELD, &1, "cancer"),
+            fielded.field_bm25_weight(TEXT_FIELD, &1, "cell"),
+        ];
+        let query_weights = [fielded.idf("cancer"), fielded.idf("cell")];
+        let dot = doc[0] * query_weights[0] + doc[1] * query_weights[1];
+        let expected = dot / (fielded.vector_length(&doc) * fielded.vector_length(&query_weights));
+        assert!((fielded_score - expected).abs() < 1e-6);
+        assert!((fielded_score - merged_score).abs() > 1e-3);
+    }
+
+    #[test]
+    fn repeated_query_terms_follow_the_tf_scheme() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 3), ("gene", 1)]);
+        let mut scores = vec![];
+        for (scheme, tf_weight) in [
+            (QueryTfScheme::Raw, 3.0),
+            (QueryTfScheme::Log, 1.0 + 3f32.ln()),
+            (QueryTfScheme::Boolean, 1.0),
+        ] {
+            ranking.query_tf_scheme = scheme;
+            let weights = ranking.query_vector(&q).weights;
+            assert_eq!(weights[0], ("cell", ranking.idf("cell") * tf_weight));
+            assert_eq!(weights[1], ("gene", ranking.idf("gene")));
+            scores.push(ranking.cosine_similarity(&1, &q));
+        }
+        // document 1 only has cell, so the more cell counts the closer it is
+        assert!(scores[0] > scores[1]);
+        assert!(scores[1] > scores[2]);
+    }
+
+    #[test]
+    fn custom_scorer_scores_every_candidate() {
+        struct Constant;
+        impl Scorer for Constant {
+            fn score(&self, _: &ScoringContext, _: u32, _: &TokenizedQuery) -> f32 {
+                1.0
+            }
+        }
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let queries = [
+            query(1, &[("cell", 1), ("gene", 1)]),
+            query(2, &[("tumor", 1)]),
+        ];
+        let results = ranking.rank_documents_with_scorer(&queries, &Constant);
+        assert_eq!(results[&1].len(), 4);
+        assert_eq!(results[&2].len(), 1);
+        for result in results.values().flatten() {
+            assert_eq!(result.score, 1.0);
+        }
+        // the built in scorers give the ranked scores
+        let bm25 = ranking.rank_documents_with_scorer(&queries, &Bm25Scorer);
+        for result in &bm25[&1] {
+            assert_eq!(
+                result.score,
+                ranking.bm25_score(&result.doc_id, &queries[0])
+            );
+        }
+        let cosine = ranking.rank_documents_with_scorer(&queries, &CosineScorer);
+        assert_eq!(cosine, ranking.rank_documents(&queries));
+        // a scorer reads the statistics from the context
+        struct CellTf;
+        impl Scorer for CellTf {
+            fn score(&self, ctx: &ScoringContext, doc_id: u32, _: &TokenizedQuery) -> f32 {
+                ctx.tf(doc_id, "cell") as f32
+            }
+        }
+        let results = ranking.rank_documents_with_scorer(&queries, &CellTf);
+        for result in &results[&1] {
+            assert_eq!(result.score, ranking.tf(&result.doc_id, "cell") as f32);
+        }
+    }
+
+    #[test]
+    fn repeated_queries_are_served_from_the_cache() {
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        assert_eq!(ranking.query_cache_stats(), None);
+        ranking.enable_query_cache(8);
+        let q = query(1, &[("cell", 1), ("gene", 1)]);
+        let first = ranking.rank_query(&q);
+        assert_eq!(ranking.query_cache_stats(), Some((0, 1)));
+        // the misses count the queries actually scored
+        let second = ranking.rank_query(&q);
+        assert_eq!(ranking.query_cache_stats(), Some((1, 1)));
+        assert_eq!(ranked_ids(&first), ranked_ids(&second));
+
+        // a new index clears the cached results, the query is scored again
+        let (new_lengths, new_index) = build(&[(1, &[("cell", 1)]), (5, &[("gene", 4)])]);
+        ranking.set_documents(&new_index, &new_lengths);
+        let third = ranking.rank_query(&q);
+        assert_eq!(ranking.query_cache_stats(), Some((1, 2)));
+        assert_eq!(ranked_ids(&third).len(), 2);
+    }
+
+    #[test]
+    fn coord_favors_the_document_covering_more_terms() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("gene", 1)]),
+            (2, &[("tumor", 2), ("blood", 1)]),
+            (3, &[("cell", 1), ("heart", 1)]),
+            (4, &[("gene", 1), ("lung", 1)]),
+        ]);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.model = ScoringModel::Bm25;
+        let q = query(1, &[("cell", 1), ("gene", 1), ("tumor", 1)]);
+        // the rare tumor outweighs the two common terms of document 1
+        assert_eq!(ranked_ids(&ranking.rank_query(&q))[0], 2);
+        ranking.coord = true;
+        assert_eq!(ranking.coord_factor(&1, &q), 2.0 / 3.0);
+        assert_eq!(ranking.coord_factor(&2, &q), 1.0 / 3.0);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q))[0], 1);
+    }
+
+    #[test]
+    fn misspelling_shares_char_ngrams() {
+        use crate::{
+            indexing::build_char_ngram_index,
+            preprocessing::{char_ngrams, TokenizedDocument},
+        };
+        let immune: HashSet<String> = char_ngrams("immune", 3).into_iter().collect();
+        let imune: HashSet<String> = char_ngrams("imune", 3).into_iter().collect();
+        // #im, mun, une and ne# of the 5 grams of imune
+        assert_eq!(immune.intersection(&imune).count(), 4);
+
+        let tokens = |terms: &[&str]| terms.iter().map(|t| (t.to_string(), 1)).collect();
+        let documents = vec![
+            TokenizedDocument::new(1, HashMap::new(), tokens(&["immune", "cell"])),
+            TokenizedDocument::new(2, HashMap::new(), tokens(&["cardiac", "arrest"])),
+            TokenizedDocument::new(3, HashMap::new(), tokens(&["blood", "pressure"])),
+        ];
+        let (doc_lengths, index) = build(&[
+            (1, &[("immune", 1), ("cell", 1)]),
+            (2, &[("cardiac", 1), ("arrest", 1)]),
+            (3, &[("blood", 1), ("pressure", 1)]),
+        ]);
+        let ngram_index = build_char_ngram_index(&documents, 3);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("imune", 1)]);
+        assert!(ranking.rank_query(&q).is_empty());
+        assert_eq!(
+            ranked_ids(&ranking.rank_char_ngrams(&ngram_index, &q, 3))[0],
+            1
+        );
+    }
+
+    #[test]
+    fn top_k_overrides_apply_per_query() {
+        let docs: Vec<(u32, [(&str, TermFreq); 1])> = (0..30u32)
+            .map(|doc_id| (doc_id, [("cell", doc_id % 5 + 1)]))
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let (doc_lengths, index) = build(&docs);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.top_k = 10;
+        let overrides = HashMap::from([(2, 25)]);
+        ranking.top_k_overrides = Some(&overrides);
+        let queries = [
+            query(1, &[("cell", 1)]),
+            query(2, &[("cell", 1)]),
+            query(3, &[("cell", 1)]),
+        ];
+        let results = ranking.rank_documents(&queries);
+        assert_eq!(results[&1].len(), 10);
+        assert_eq!(results[&2].len(), 25);
+        assert_eq!(results[&3].len(), 10);
+    }
+
+    #[test]
+    fn identical_runs_write_identical_files() {
+        // every document has the same score for cell, only the tie break orders them
+        let docs: Vec<(u32, [(&str, TermFreq); 2])> = (0..40u32)
+            .map(|doc_id| (doc_id * 7 % 40, [("cell", 1), ("gene", doc_id % 2 + 1)]))
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let queries = [
+            query(1, &[("cell", 1)]),
+            query(2, &[("cell", 1), ("gene", 1)]),
+        ];
+        let path = temp_path("deterministic.tsv");
+        let mut runs = vec![];
+        for _ in 0..2 {
+            // rebuilt so the hash maps get a new iteration order
+            let (doc_lengths, index) = build(&docs);
+            let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+            save_results_to_file(ranking.rank_documents(&queries), "run", &path, None).unwrap();
+            runs.push(std::fs::read(&path).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(!runs[0].is_empty());
+        assert_eq!(runs[0], runs[1]);
+    }
+
+    #[test]
+    fn heap_top_k_equals_the_full_ranking_cut() {
+        let docs: Vec<(u32, [(&str, TermFreq); 2])> = (0..50u32)
+            .map(|doc_id| (doc_id, [("cell", doc_id % 3 + 1), ("gene", doc_id % 4 + 1)]))
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let (doc_lengths, index) = build(&docs);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let q = query(1, &[("cell", 1), ("gene", 2)]);
+        for model in MODELS {
+            ranking.model = model;
+            ranking.top_k = usize::MAX;
+            let full = ranked_ids(&ranking.rank_query(&q));
+            assert_eq!(full.len(), 50);
+            for top_k in [1, 7, 20] {
+                ranking.top_k = top_k;
+                assert_eq!(ranked_ids(&ranking.rank_query(&q)), full[..top_k]);
+            }
+        }
+    }
+
+    #[test]
+    fn smoothing_constants_change_the_idf() {
+        // N = 4 documents, df = 2 for cell
+        let (doc_lengths, index) = corpus();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        // ln((4 - 2 + 0.5) / (2 + 0.5) + 1)
+        assert!((ranking.idf("cell") - 2f32.ln()).abs() < 1e-6);
+        ranking.idf_smoothing = 1.0;
+        ranking.idf_offset = 0.5;
+        // ln((4 - 2 + 1) / (2 + 1) + 0.5)
+        assert!((ranking.idf("cell") - 1.5f32.ln()).abs() < 1e-6);
+        // df = 1 for tumor, ln((4 - 1 + 1) / (1 + 1) + 0.5)
+        assert!((ranking.idf("tumor") - 2.5f32.ln()).abs() < 1e-6);
+        // without the offset the variant is the probabilistic idf
+        ranking.idf_offset = 0.0;
+        assert_eq!(ranking.idf("tumor"), 2f32.ln());
+        ranking.idf_variant = IdfVariant::Bm25Probabilistic;
+        assert_eq!(ranking.idf("tumor"), 2f32.ln());
+    }
+
+    #[test]
+    fn documents_with_an_excluded_term_are_dropped() {
+        use crate::preprocessing::{process_query, tokenize_text, Query};
+        let stems = tokenize_text("immune vaccine response", &HashSet::new());
+        let (immune, vaccine, response) = (stems[0].as_str(), stems[1].as_str(), stems[2].as_str());
+        let (doc_lengths, index) = build(&[
+            (1, &[(immune, 2), (vaccine, 1)]),
+            (2, &[(immune, 1), (response, 1)]),
+        ]);
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        let raw = |text: &str| Query {
+            _id: "1".to_string(),
+            text: text.to_string(),
+            metadata: HashMap::new(),
+        };
+        let q = process_query(raw("immune"), &HashSet::new()).unwrap();
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [1, 2]);
+        let q = process_query(raw("immune -vaccine"), &HashSet::new()).unwrap();
+        assert!(q.excluded.contains(vaccine));
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [2]);
+    }
+
+    #[test]
+    fn f64_accumulation_breaks_an_f32_tie() {
+        let (doc_lengths, index) = build(&[
+            (1, &[("cell", 1), ("heart", 1), ("lung", 1), ("liver", 1)]),
+            (2, &[("cell", 1), ("gene", 1), ("tumor", 1), ("blood", 1)]),
+        ]);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.model = ScoringModel::Bm25;
+        let big = ranking.bm25_weight(&2, "cell");
+        assert_eq!(big, ranking.bm25_weight(&1, "cell"));
+        let ulp = f32::from_bits(big.to_bits() + 1) - big;
+        // each small term adds 0.4 ulp to document 2, lost when added to big in f32
+        let mut q = query(1, &[("cell", 1), ("gene", 1), ("tumor", 1), ("blood", 1)]);
+        for term in ["gene", "tumor", "blood"] {
+            let boost = 0.4 * ulp / ranking.bm25_weight(&2, term);
+            q.boosts.insert(term.to_string(), boost);
+        }
+        let f32_sum = ["gene", "tumor", "blood"].iter().fold(big, |sum, term| {
+            sum + ranking.bm25_weight(&2, term) * q.boost(term)
+        });
+        assert_eq!(f32_sum, ranking.bm25_score(&1, &q));
+        // 1.2 ulp in f64 rounds up to the next f32
+        assert_eq!(ranking.bm25_score(&2, &q), big + ulp);
+        assert_eq!(ranked_ids(&ranking.rank_query(&q)), [2, 1]);
+    }
+
+    // Minimal trec_eval -J check: 6 columns split by single spaces, queries in one block each,
+    // ranks 1..n, non increasing scores, no repeated document and one run tag
+    fn check_trec_strict(text: &str) -> Result<(), String> {
+        let mut done: HashSet<&str> = HashSet::new();
+        let mut current: Option<(&str, usize, f64)> = None;
+        let mut docs: HashSet<(&str, &str)> = HashSet::new();
+        let mut run_tags: HashSet<&str> = HashSet::new();
+        for (n, line) in text.lines().enumerate() {
+            let columns: Vec<&str> = line.split(' ').collect();
+            let [query_id, q0, doc_id, rank, score, run_tag] = columns[..] else {
+                return Err(format!("line {}: {} columns", n + 1, columns.len()));
+            };
+            let rank: usize = rank.parse().map_err(|_| format!("line {}: rank", n + 1))?;
+            let score: f64 = score
+                .parse()
+                .map_err(|_| format!("line {}: score", n + 1))?;
+            if q0 != "Q0" || !docs.insert((query_id, doc_id)) {
+                return Err(format!("line {}: Q0 or repeated document", n + 1));
+            }
+            run_tags.insert(run_tag);
+            let expected_rank = match current {
+                Some((id, last_rank, last_score)) if id == query_id => {
+                    if score > last_score {
+                        return Err(format!("line {}: score increases", n + 1));
+                    }
+                    last_rank + 1
+                }
+                _ => {
+                    if !done.insert(query_id) {
+                        return Err(format!("line {}: query {} split", n + 1, query_id));
+                    }
+                    1
+                }
+            };
+            if rank != expected_rank {
+                return Err(format!(
+                    "line {}: rank {} for {}",
+                    n + 1,
+                    rank,
+                    expected_rank
+                ));
+            }
+            current = Some((query_id, rank, score));
+        }
+        match run_tags.len() {
+            0 | 1 => Ok(()),
+            _ => Err("several run tags".to_string()),
+        }
+    }
+
+    #[test]
+    fn strict_output_passes_the_validator() {
+        // equal scores once written and a score that's nearly equal
+        let scores = results(&[
+            (2, 7, 0.5),
+            (2, 30, 0.5),
+            (2, 4, 0.5000001),
+            (10, 1, 3.0),
+            (10, 2, 1e-9),
+            (1, 5, 2.0),
+        ]);
+        let path = temp_path("strict.tsv");
+        save_results_trec_strict(&scores, "bm25", &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(check_trec_strict(&written), Ok(()));
+        assert_eq!(written.lines().count(), 6);
+        assert!(written.starts_with("1 Q0 5 1 2.000000 bm25\n"));
+        // the default output has the double spaces trec_eval -J rejects
+        save_results_to_file(scores.clone(), "bm25", &path, None).unwrap();
+        let default = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(check_trec_strict(&default).is_err());
+        assert!(save_results_trec_strict(&scores, "two words", &path).is_err());
+    }
+
+    #[test]
+    fn raw_text_search_ranks_the_matching_documents() {
+        use crate::{
+            indexing::{index_documents, IndexConfig},
+            preprocessing::Document,
+        };
+        let document = |doc_id: &str, text: &str| Document {
+            _id: doc_id.to_string(),
+            title: String::new(),
+            text: text.to_string(),
+        };
+        let documents = vec![
+            document("1", "Blood cells carry oxygen through the blood"),
+            document("2", "Heart cells contract"),
+            document("3", "Gene expression in tumors"),
+        ];
+        let stopwords: HashSet<String> =
+            ["the", "of", "in"].iter().map(|w| w.to_string()).collect();
+        let config = IndexConfig::default();
+        let (index, doc_lengths) = index_documents(documents, &stopwords, &config).unwrap();
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.index_fingerprint = Some(PreprocessingFingerprint::new(
+            &stopwords,
+            &config.preprocess,
+        ));
+
+        let results = ranking
+            .search("The BLOOD of the cells", &stopwords, &config.preprocess, 10)
+            .unwrap();
+        let doc_ids: Vec<u32> = results.iter().map(|(doc_id, _)| *doc_id).collect();
+        assert_eq!(doc_ids, [1, 2]);
+        assert!(results[0].1 > results[1].1);
+        let top = ranking
+            .search("blood cells", &stopwords, &config.preprocess, 1)
+            .unwrap();
+        assert_eq!(top.len(), 1);
+
+        // queries preprocessed differently than the index are rejected
+        assert!(ranking
+            .search("blood cells", &HashSet::new(), &config.preprocess, 10)
+            .is_err());
+    }
+
+    #[test]
+    fn term_at_a_time_matches_document_at_a_time() {
+        let terms = ["cell", "protein", "gene", "tumor", "blood"];
+        let docs: Vec<(u32, Vec<(&str, TermFreq)>)> = (0..30u32)
+            .map(|doc_id| {
+                let tokens = terms
+                    .iter()
+                    .enumerate()
+                    .filter(|(n, _)| (doc_id as usize + n) % 4 != 1)
+                    .map(|(n, term)| (*term, (doc_id + n as u32) % 3 + 1))
+                    .collect();
+                (doc_id, tokens)
+            })
+            .collect();
+        let docs: Vec<(u32, &[(&str, TermFreq)])> = docs
+            .iter()
+            .map(|(doc_id, tokens)| (*doc_id, tokens.as_slice()))
+            .collect();
+        let (doc_lengths, index) = build(&docs);
+        let mut ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        ranking.top_k = 10;
+        let queries = [
+            query(1, &[("cell", 1), ("gene", 2)]),
+            query(2, &[("tumor", 1), ("blood", 1), ("heart", 1)]),
+        ];
+        for model in MODELS {
+            ranking.model = model;
+            let daat = ranking.rank_documents(&queries);
+            let taat = ranking.rank_documents_taat(&queries);
+            assert_eq!(taat.len(), 2);
+            for (query_id, results) in &daat {
+                assert_eq!(ranked_ids(&taat[query_id]), ranked_ids(results));
+                // the terms are summed in another order, only the rounding may differ
+                for (a, b) in taat[query_id].iter().zip(results) {
+                    assert!((a.score - b.score).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_logs_the_idf_of_each_term() {
+        use std::sync::Mutex;
+        // Keeps every record, other tests log concurrently so only this query's lines are checked
+        struct Capture(Mutex<Vec<String>>);
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+        // the logger is global and only set once
+        let _ = log::set_logger(&CAPTURE);
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let (doc_lengths, index) = corpus();
+        let ranking = Ranking::init(&doc_lengths, &index, 1.2, 0.75);
+        // other tests log their queries too, the traced query's id isn't used by any of them
+        let traced = query(90, &[("gene", 1), ("cell", 1), ("heart", 1)]);
+        ranking.rank_query(&traced);
+        let prefix = format!("query {}:", traced._id);
+        let lines: Vec<String> = CAPTURE
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|line| line.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let idf_line = |term: &str| {
+            format!(
+                "{} term {:?} idf {:.4} df 2",
+                prefix,
+                term,
+                ranking.idf(term)
+            )
+        };
+        assert_eq!(lines[0], idf_line("cell"));
+        assert_eq!(lines[1], idf_line("gene"));
+        assert_eq!(
+            lines[2],
+            format!("{} term \"heart\" not in the index", prefix)
+        );
+        assert!(lines[3].starts_with(&format!("{} rank 1 doc ", prefix)));
+    }
+}
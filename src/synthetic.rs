@@ -0,0 +1,111 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::preprocessing::{TermFreq, TokenizedDocument, TokenizedQuery};
+
+// splitmix64, enough for fixtures and the same sequence on every platform for a given seed
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in [low, high]
+    fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next_f64() * (high - low + 1) as f64) as usize
+    }
+
+    // Skewed towards the first terms like a real vocabulary, term n is named "t{n}"
+    fn term(&mut self, vocab_size: usize) -> String {
+        let n = (self.next_f64().powi(3) * vocab_size as f64) as usize;
+        format!("t{}", n.min(vocab_size - 1))
+    }
+}
+
+// Documents with ids 1..=num_docs and only a text field, lengths are uniform in [1, 2 * avg_len - 1]
+pub fn generate_synthetic_corpus(
+    num_docs: usize,
+    vocab_size: usize,
+    avg_len: usize,
+    seed: u64,
+) -> Vec<TokenizedDocument> {
+    let mut rng = SeededRng(seed);
+    (1..=num_docs)
+        .map(|doc_id| {
+            let length = rng.range(1, (2 * avg_len).max(2) - 1);
+            let mut tokens: HashMap<String, TermFreq> = HashMap::new();
+            for _ in 0..length {
+                *tokens.entry(rng.term(vocab_size.max(1))).or_insert(0) += 1;
+            }
+            TokenizedDocument::new(doc_id as u32, HashMap::new(), tokens)
+        })
+        .collect()
+}
+
+// Queries with ids 1..=num_queries over the same vocabulary as generate_synthetic_corpus
+pub fn generate_synthetic_queries(
+    num_queries: usize,
+    vocab_size: usize,
+    query_len: usize,
+    seed: u64,
+) -> Vec<TokenizedQuery> {
+    let mut rng = SeededRng(seed);
+    (1..=num_queries as u32)
+        .map(|query_id| {
+            let mut tokens: HashMap<String, TermFreq> = HashMap::new();
+            for _ in 0..query_len {
+                *tokens.entry(rng.term(vocab_size.max(1))).or_insert(0) += 1;
+            }
+            TokenizedQuery {
+                _id: query_id,
+                tokens,
+                metadata: HashMap::new(),
+                boosts: HashMap::new(),
+                bigrams: HashMap::new(),
+                excluded: HashSet::new(),
+                fingerprint: None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (doc_id, fields) of each document, TokenizedDocument doesn't implement PartialEq
+    type Fields = Vec<(u32, HashMap<String, HashMap<String, TermFreq>>)>;
+
+    fn fields(documents: Vec<TokenizedDocument>) -> Fields {
+        documents
+            .into_iter()
+            .map(|doc| (doc._id, doc.fields))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_corpus() {
+        let first = fields(generate_synthetic_corpus(50, 100, 20, 7));
+        let second = fields(generate_synthetic_corpus(50, 100, 20, 7));
+        assert_eq!(first.len(), 50);
+        assert_eq!(first, second);
+        assert_ne!(first, fields(generate_synthetic_corpus(50, 100, 20, 8)));
+
+        let tokens = |queries: Vec<TokenizedQuery>| -> Vec<HashMap<String, TermFreq>> {
+            queries.into_iter().map(|query| query.tokens).collect()
+        };
+        assert_eq!(
+            tokens(generate_synthetic_queries(10, 100, 4, 7)),
+            tokens(generate_synthetic_queries(10, 100, 4, 7))
+        );
+    }
+}